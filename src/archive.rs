@@ -0,0 +1,362 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use log::{debug, warn};
+use serde_json::json;
+use serenity::model::channel::{Attachment, Message};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::Config, discord::DiscordApi};
+
+/// The directory in the archive attachments are stored in.
+const ATTACHMENTS_DIR: &str = "attachments";
+
+/// Appends the messages to the archive under `root` and syncs it to disk, so
+/// they can be deleted safely. There is one JSON Lines file per guild,
+/// channel and day the messages were sent on:
+/// `<root>/<guild id>/<channel id>/<YYYY-MM-DD>.jsonl`.
+///
+/// The `sha256` of downloaded attachments is added to their metadata.
+///
+/// Returns the messages that were archived. The others couldn't be written and
+/// must not be deleted.
+pub fn archive_messages<'a>(
+    root: &Path,
+    guild_id: u64,
+    channel_id: u64,
+    messages: &[&'a Message],
+    attachments: &HashMap<u64, String>,
+) -> Vec<&'a Message> {
+    let mut by_date: BTreeMap<NaiveDate, Vec<&Message>> = BTreeMap::new();
+    for msg in messages {
+        by_date
+            .entry(msg.timestamp.date_naive())
+            .or_default()
+            .push(*msg);
+    }
+
+    let mut archived = vec![];
+    for (date, messages) in by_date {
+        let path = archive_path(root, guild_id, channel_id, date);
+        match append(&path, guild_id, &messages, attachments) {
+            Ok(()) => archived.extend(messages),
+            Err(e) => warn!(
+                guild_id:% = guild_id, channel_id:% = channel_id;
+                "Could not archive {} messages, they won't be deleted: {:#}",
+                messages.len(),
+                e
+            ),
+        }
+    }
+    archived
+}
+
+fn archive_path(root: &Path, guild_id: u64, channel_id: u64, date: NaiveDate) -> PathBuf {
+    root.join(guild_id.to_string())
+        .join(channel_id.to_string())
+        .join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+}
+
+/// Appends the messages to the file, one JSON object per line, and waits until
+/// they're on disk.
+fn append(
+    path: &Path,
+    guild_id: u64,
+    messages: &[&Message],
+    attachments: &HashMap<u64, String>,
+) -> Result<()> {
+    let mut lines = vec![];
+    for msg in messages {
+        let mut record = serde_json::to_value(msg)?;
+        // Messages fetched over HTTP don't know their guild
+        record["guild_id"] = json!(guild_id);
+        for (i, attachment) in msg.attachments.iter().enumerate() {
+            if let Some(sha256) = attachments.get(attachment.id.as_u64()) {
+                record["attachments"][i]["sha256"] = json!(sha256);
+            }
+        }
+        serde_json::to_writer(&mut lines, &record)?;
+        lines.push(b'\n');
+    }
+
+    let dir = path.parent().context("Archive file has no directory")?;
+    let created = !path.exists();
+    fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    file.write_all(&lines)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Could not write {}", path.display()))?;
+    if created {
+        // The new file, channel and guild directories are only durable once
+        // their parents are synced too.
+        for dir in path.ancestors().skip(1).take(3) {
+            sync_dir(dir)?;
+        }
+    }
+    Ok(())
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Could not sync {}", dir.display()))
+}
+
+/// Downloads the attachments of the messages to `<root>/attachments`. Each file
+/// is named by the SHA-256 of its content, so it's only stored once.
+/// Attachments larger than `ATTACHMENT_MAX_SIZE` or with a type that isn't in
+/// `ATTACHMENT_TYPES` are skipped.
+///
+/// Returns the messages whose attachments were all stored or skipped and the
+/// hashes of the stored attachments by their id. The other messages must not
+/// be deleted.
+pub async fn download_attachments<'a>(
+    client: &impl DiscordApi,
+    root: &Path,
+    config: &Config,
+    messages: &[&'a Message],
+) -> (Vec<&'a Message>, HashMap<u64, String>) {
+    let dir = root.join(ATTACHMENTS_DIR);
+    let mut downloaded = vec![];
+    let mut hashes = HashMap::new();
+    'messages: for msg in messages {
+        for attachment in &msg.attachments {
+            if !is_allowed(attachment, config) {
+                debug!(
+                    message_id:% = msg.id;
+                    "Not downloading attachment {} ({} bytes)",
+                    attachment.filename,
+                    attachment.size
+                );
+                continue;
+            }
+            let stored = match client.download(&attachment.url).await {
+                Ok(content) => store(&dir, &content),
+                Err(e) => Err(e.context("Could not download")),
+            };
+            match stored {
+                Ok(sha256) => {
+                    hashes.insert(*attachment.id.as_u64(), sha256);
+                }
+                Err(e) => {
+                    warn!(
+                        message_id:% = msg.id;
+                        "Could not archive attachment {} of message {}, it won't be deleted: {:#}",
+                        attachment.filename,
+                        msg.id,
+                        e
+                    );
+                    continue 'messages;
+                }
+            }
+        }
+        downloaded.push(*msg);
+    }
+    (downloaded, hashes)
+}
+
+/// Returns whether the attachment is within the configured size and type
+/// limits.
+fn is_allowed(attachment: &Attachment, config: &Config) -> bool {
+    let size_allowed = config
+        .attachment_max_size
+        .is_none_or(|max_size| attachment.size <= max_size);
+    let type_allowed = config.attachment_types.as_ref().is_none_or(|types| {
+        Path::new(&attachment.filename)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| types.contains(&extension.to_lowercase()))
+    });
+    size_allowed && type_allowed
+}
+
+/// Writes the content to the directory unless it's already there and returns
+/// its hash.
+fn store(dir: &Path, content: &[u8]) -> Result<String> {
+    let sha256 = format!("{:x}", Sha256::digest(content));
+    let path = dir.join(&sha256);
+    if path.exists() {
+        return Ok(sha256);
+    }
+
+    fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    // Written under a temporary name, so a file with the hash is complete
+    let tmp_path = dir.join(format!("{}.tmp", sha256));
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("Could not create {}", tmp_path.display()))?;
+    file.write_all(content)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, &path)
+        .with_context(|| format!("Could not rename {}", tmp_path.display()))?;
+    sync_dir(dir)?;
+    sync_dir(dir.parent().context("Attachments have no archive")?)?;
+    Ok(sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::fake::{self, FakeDiscord};
+    use chrono::{TimeZone, Utc};
+    use serde_json::Value;
+    use std::env;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "discord-retention-bot-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_archive_messages() {
+        let root = temp_dir("archive");
+        let day = Utc.with_ymd_and_hms(2021, 1, 2, 12, 0, 0).unwrap();
+        let message = |id, time| {
+            let fields = json!({
+                "content": format!("message {}", id),
+                "attachments": [fake::attachment(4, 2, "foo.txt", 3)],
+            });
+            fake::message_with(id, 2, time, fields)
+        };
+        let messages = [
+            message(10, day),
+            message(11, day + chrono::Duration::days(1)),
+            message(12, day + chrono::Duration::hours(1)),
+        ];
+        let candidates: Vec<&Message> = messages.iter().collect();
+
+        let attachments = HashMap::from([(4, "abc".to_string())]);
+        let archived = archive_messages(&root, 1, 2, &candidates, &attachments);
+        assert_eq!(archived.len(), 3);
+        // Appends to the existing files
+        archive_messages(&root, 1, 2, &candidates[..1], &HashMap::new());
+
+        let first_day = fs::read_to_string(root.join("1/2/2021-01-02.jsonl")).unwrap();
+        let records: Vec<Value> = first_day
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["id"], 10);
+        assert_eq!(records[0]["guild_id"], 1);
+        assert_eq!(records[0]["content"], "message 10");
+        assert_eq!(records[0]["attachments"][0]["filename"], "foo.txt");
+        assert_eq!(records[0]["attachments"][0]["sha256"], "abc");
+        assert!(records[2]["attachments"][0].get("sha256").is_none());
+        assert_eq!(records[1]["id"], 12);
+        assert_eq!(records[2]["id"], 10);
+
+        let second_day = fs::read_to_string(root.join("1/2/2021-01-03.jsonl")).unwrap();
+        assert_eq!(second_day.lines().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_archive_messages_failure() {
+        // The guild directory can't be created where a file is
+        let root = temp_dir("archive-failure");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("1"), "").unwrap();
+        let msg = fake::message_with(
+            10,
+            2,
+            Utc::now(),
+            json!({ "attachments": [fake::attachment(4, 2, "foo.txt", 3)] }),
+        );
+
+        let archived = archive_messages(&root, 1, 2, &[&msg], &HashMap::new());
+        assert!(archived.is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let msg = fake::message_with(
+            10,
+            2,
+            Utc::now(),
+            json!({ "attachments": [fake::attachment(4, 2, "foo.txt", 3)] }),
+        );
+        let attachment = &msg.attachments[0];
+        let mut config = Config::default();
+        assert!(is_allowed(attachment, &config));
+
+        config.attachment_max_size = Some(2);
+        assert!(!is_allowed(attachment, &config));
+        config.attachment_max_size = Some(3);
+        assert!(is_allowed(attachment, &config));
+
+        config.attachment_types = Some(vec!["png".to_string(), "pdf".to_string()]);
+        assert!(!is_allowed(attachment, &config));
+        config.attachment_types = Some(vec!["txt".to_string()]);
+        assert!(is_allowed(attachment, &config));
+    }
+
+    #[tokio::test]
+    async fn test_download_attachments() {
+        let root = temp_dir("download");
+        let discord = FakeDiscord::default();
+        let message = |id, time| {
+            let fields = json!({
+                "content": format!("message {}", id),
+                "attachments": [fake::attachment(4, 2, "foo.txt", 3)],
+            });
+            fake::message_with(id, 2, time, fields)
+        };
+        let downloaded_msg = message(10, Utc::now());
+        discord.add_file(&downloaded_msg.attachments[0].url, b"foo");
+        let mut missing_msg = message(11, Utc::now());
+        missing_msg.attachments[0].url = "https://cdn.discordapp.com/missing".to_string();
+        let mut skipped_msg = message(12, Utc::now());
+        skipped_msg.attachments[0].size = 1024;
+        let config = Config {
+            attachment_max_size: Some(100),
+            ..Config::default()
+        };
+
+        let (downloaded, hashes) = download_attachments(
+            &discord,
+            &root,
+            &config,
+            &[&downloaded_msg, &missing_msg, &skipped_msg],
+        )
+        .await;
+        let ids: Vec<u64> = downloaded.iter().map(|msg| *msg.id.as_u64()).collect();
+        assert_eq!(ids, vec![10, 12]);
+        assert_eq!(hashes.len(), 1);
+        let path = root.join(ATTACHMENTS_DIR).join(&hashes[&4]);
+        assert_eq!(fs::read(path).unwrap(), b"foo");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_store() {
+        let root = temp_dir("attachments");
+        let dir = root.join(ATTACHMENTS_DIR);
+        let sha256 = store(&dir, b"foo").unwrap();
+        assert_eq!(
+            sha256,
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae"
+        );
+        assert_eq!(fs::read(dir.join(&sha256)).unwrap(), b"foo");
+
+        // The same content is stored once
+        assert_eq!(store(&dir, b"foo").unwrap(), sha256);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
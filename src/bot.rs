@@ -1,320 +1,1659 @@
 use anyhow::{Context, Result};
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use futures::stream::{FuturesUnordered, StreamExt};
-use log::{error, info};
+use log::{error, info, warn};
+use serde_json::json;
 use serenity::{
-    http::{client::Http, GuildPagination},
+    http::HttpError,
     model::{
         channel::{ChannelType, GuildChannel, Message},
         guild::GuildInfo,
-        id::GuildId,
+        id::{ChannelId, MessageId},
     },
 };
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration as StdDuration, Instant},
+};
 
-pub async fn run(
-    client: &Http,
-    channel_retention: &HashMap<String, Duration>,
-    delete_pinned: bool,
-) -> Result<()> {
-    let guilds = get_all_guilds(&client).await?;
+use crate::{
+    archive::{archive_messages, download_attachments},
+    config::{format_duration, Config, ParseChannelConfigError, Rule, RuleMatch},
+    discord::DiscordApi,
+    logging::AUDIT_TARGET,
+    metrics::{self, ChannelLabels},
+    ratelimit::RateLimits,
+    state::{ChannelState, State},
+};
+
+/// The maximum number of messages Discord accepts in one bulk delete request.
+const BULK_DELETE_MAX_MESSAGES: usize = 100;
+
+/// The minimum number of messages Discord accepts in one bulk delete request.
+const BULK_DELETE_MIN_MESSAGES: usize = 2;
+
+/// Messages older than this can't be bulk deleted. It's a bit less than the
+/// two weeks Discord allows, so messages don't age out while we're busy.
+fn bulk_delete_max_age() -> Duration {
+    Duration::weeks(2) - Duration::minutes(5)
+}
+
+/// The first second of 2015, the epoch of Discord's snowflake ids.
+const DISCORD_EPOCH_MILLIS: i64 = 1_420_070_400_000;
+
+/// Creates the smallest message id with the given creation time.
+pub fn snowflake_at(time: DateTime<Utc>) -> u64 {
+    ((time.timestamp_millis() - DISCORD_EPOCH_MILLIS).max(0) as u64) << 22
+}
+
+/// Discord's error code for a message that doesn't exist (anymore).
+pub const UNKNOWN_MESSAGE: isize = 10008;
+
+/// Discord's error code for a user that isn't a member of the guild (anymore).
+pub const UNKNOWN_MEMBER: isize = 10007;
+
+/// Discord's error code for a channel the bot can't see.
+pub const MISSING_ACCESS: isize = 50001;
+
+/// Discord's error code for a missing permission, e.g. Manage Messages.
+pub const MISSING_PERMISSIONS: isize = 50013;
+
+/// How a failed request is handled.
+#[derive(Debug, PartialEq)]
+enum Failure {
+    /// The message is already gone, which is just as good.
+    UnknownMessage,
+    /// The author left the guild, so they have no roles.
+    UnknownMember,
+    /// The bot lacks the permissions for the channel, so it's skipped.
+    Forbidden,
+    /// The token is invalid, nothing works until it's replaced.
+    Unauthorized,
+    /// Anything else, including transient errors that were retried in vain.
+    Other,
+}
+
+impl Failure {
+    fn of(err: &serenity::Error) -> Self {
+        let response = match err {
+            serenity::Error::Http(http_error) => match http_error.as_ref() {
+                HttpError::UnsuccessfulRequest(response) => response,
+                _ => return Failure::Other,
+            },
+            _ => return Failure::Other,
+        };
+        match (response.status_code.as_u16(), response.error.code) {
+            (401, _) => Failure::Unauthorized,
+            (_, UNKNOWN_MESSAGE) => Failure::UnknownMessage,
+            (_, UNKNOWN_MEMBER) => Failure::UnknownMember,
+            (403, _) | (_, MISSING_ACCESS) | (_, MISSING_PERMISSIONS) => Failure::Forbidden,
+            _ => Failure::Other,
+        }
+    }
+
+    /// Returns whether the channel can't be processed further.
+    fn aborts_channel(&self) -> bool {
+        matches!(self, Failure::Forbidden | Failure::Unauthorized)
+    }
+
+    /// Looks for a Discord error in the chain of the given error.
+    fn find(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<serenity::Error>())
+            .map_or(Failure::Other, Failure::of)
+    }
+}
+
+/// Returns whether the error can't be fixed by retrying, i.e. the token is
+/// invalid.
+pub fn is_fatal(err: &anyhow::Error) -> bool {
+    Failure::find(err) == Failure::Unauthorized
+}
+
+/// Summarizes a run over all guilds.
+#[derive(Debug, Default)]
+pub struct SweepSummary {
+    pub guilds: u64,
+    pub failed_guilds: u64,
+    pub channels: u64,
+    /// Channels that failed or where some messages couldn't be deleted.
+    pub failed_channels: u64,
+    /// Channels the bot lacks permissions for.
+    pub skipped_channels: u64,
+    pub deleted: u64,
+    /// Messages that couldn't be deleted.
+    pub failed_deletions: u64,
+    /// The time spent waiting for rate limits and backing off.
+    pub waited: StdDuration,
+}
+
+impl SweepSummary {
+    /// Returns whether all guilds and channels were processed without errors.
+    pub fn is_success(&self) -> bool {
+        self.failed_guilds == 0 && self.failed_channels == 0
+    }
+}
+
+pub async fn run(client: &impl DiscordApi, config: &Config, state: &State) -> Result<SweepSummary> {
+    let started = Instant::now();
+    let rate_limits = RateLimits::new(config.retry_budget.to_std()?);
+    let guilds = get_all_guilds(client, &rate_limits).await?;
+    // Channels that were deleted or lost their rule don't report it anymore
+    metrics::CHANNEL_RETENTION.reset();
+    metrics::CHANNEL_KEEP.reset();
 
     let mut guild_futures = FuturesUnordered::new();
     for guild in guilds {
-        guild_futures.push(process_guild(
-            &client,
-            guild,
-            &channel_retention,
-            delete_pinned,
-        ));
+        guild_futures.push(process_guild(client, guild, config, state, &rate_limits));
     }
 
+    let mut summary = SweepSummary::default();
     while let Some(res) = guild_futures.next().await {
-        if let Err(e) = res {
-            error!("Error processing guild: {}", e);
+        summary.guilds += 1;
+        // Saved after each guild, so an interrupted run doesn't lose progress
+        if let Err(e) = state.save() {
+            error!("Could not save state: {:?}", e);
+        }
+        match res {
+            Ok(guild_summary) => {
+                summary.channels += guild_summary.channels;
+                summary.failed_channels += guild_summary.failed_channels;
+                summary.skipped_channels += guild_summary.skipped_channels;
+                summary.deleted += guild_summary.deleted;
+                summary.failed_deletions += guild_summary.failed_deletions;
+            }
+            Err(e) if is_fatal(&e) => return Err(e),
+            Err(e) => {
+                summary.failed_guilds += 1;
+                error!("Error processing guild: {}", e);
+            }
         }
     }
 
+    info!(
+        "Deleted {} messages in {} channels, skipped {} channels, {} deletions failed",
+        summary.deleted, summary.channels, summary.skipped_channels, summary.failed_deletions
+    );
+    metrics::SWEEP_DURATION.observe(started.elapsed().as_secs_f64());
+    if summary.is_success() {
+        metrics::LAST_SUCCESSFUL_SWEEP.set(Utc::now().timestamp() as f64);
+    }
+    summary.waited = rate_limits.total_waited();
+    if summary.waited > StdDuration::from_secs(0) {
+        info!(
+            "Waited {:.1}s for rate limits in total",
+            summary.waited.as_secs_f64()
+        );
+    }
+    Ok(summary)
+}
+
+/// Prints all guilds and their text channels with the rule that applies.
+pub async fn list_channels(client: &impl DiscordApi, config: &Config) -> Result<()> {
+    let rate_limits = RateLimits::new(config.retry_budget.to_std()?);
+    for guild in get_all_guilds(client, &rate_limits).await? {
+        println!("{} ({})", guild.name, guild.id);
+        let channels = get_channels(client, &guild, &rate_limits).await?;
+        for (channel, rule) in channel_rules(config, &guild, &channels) {
+            match rule {
+                Ok(Some(matched)) => println!(
+                    "  #{} ({}): {} (from {})",
+                    channel.name, channel.id, matched.rule, matched.key
+                ),
+                Ok(None) => println!("  #{} ({}): no retention", channel.name, channel.id),
+                Err(e) => println!(
+                    "  #{} ({}): invalid configuration: {}",
+                    channel.name, channel.id, e
+                ),
+            }
+        }
+    }
     Ok(())
 }
 
-async fn get_all_guilds(client: &Http) -> Result<Vec<GuildInfo>> {
+/// Returns whether the bot is in any guild. This fails if the token isn't
+/// accepted.
+pub async fn has_guilds(client: &impl DiscordApi) -> Result<bool> {
+    let (guilds, _) = client.get_guilds(0, 1).await;
+    Ok(!guilds?.is_empty())
+}
+
+async fn get_all_guilds(
+    client: &impl DiscordApi,
+    rate_limits: &RateLimits,
+) -> Result<Vec<GuildInfo>> {
     let mut last_guild_id = Some(0u64);
     let mut guilds: Vec<GuildInfo> = vec![];
     while let Some(after) = last_guild_id {
-        let mut batch = client
-            .get_guilds(&GuildPagination::After(GuildId(after)), 100)
+        let mut batch = rate_limits
+            .retry(None, "GET /users/@me/guilds", || {
+                client.get_guilds(after, 100)
+            })
             .await?;
-        guilds.append(&mut batch);
         last_guild_id = batch.last().map(|guild| *guild.id.as_u64());
+        guilds.append(&mut batch);
     }
     Ok(guilds)
 }
 
+async fn get_channels(
+    client: &impl DiscordApi,
+    guild: &GuildInfo,
+    rate_limits: &RateLimits,
+) -> Result<Vec<GuildChannel>> {
+    let guild_id = *guild.id.as_u64();
+    let route = format!("GET /guilds/{}/channels", guild_id);
+    rate_limits
+        .retry(Some(guild_id), &route, || client.get_channels(guild_id))
+        .await
+        .context("Could not get channels")
+}
+
+/// Resolves the rule of every text channel of the guild.
+fn channel_rules<'a>(
+    config: &'a Config,
+    guild: &GuildInfo,
+    channels: &'a [GuildChannel],
+) -> Vec<(
+    &'a GuildChannel,
+    Result<Option<RuleMatch<'a>>, ParseChannelConfigError>,
+)> {
+    let categories: HashMap<ChannelId, &GuildChannel> = channels
+        .iter()
+        .filter(|channel| channel.kind == ChannelType::Category)
+        .map(|category| (category.id, category))
+        .collect();
+    channels
+        .iter()
+        .filter(|channel| channel.kind == ChannelType::Text)
+        .map(|channel| {
+            let category = channel
+                .category_id
+                .and_then(|category_id| categories.get(&category_id).copied());
+            (channel, config.retention.get(guild, channel, category))
+        })
+        .collect()
+}
+
 async fn process_guild(
-    client: &Http,
+    client: &impl DiscordApi,
     guild: GuildInfo,
-    channel_retention: &HashMap<String, Duration>,
-    delete_pinned: bool,
-) -> Result<()> {
-    info!("Processing guild {}", guild.name);
-    let channels = client
-        .get_channels(*guild.id.as_u64())
-        .await
-        .context("Could not get channels")?;
-    let default_retention = channel_retention.get("*");
-    for channel in channels {
-        if channel.kind != ChannelType::Text {
-            continue;
-        }
+    config: &Config,
+    state: &State,
+    rate_limits: &RateLimits,
+) -> Result<SweepSummary> {
+    info!(guild_id:% = guild.id; "Processing guild {}", guild.name);
+    let channels = get_channels(client, &guild, rate_limits).await?;
 
-        let max_age = match channel_retention.get(&channel.name).or(default_retention) {
-            Some(max_age) => max_age,
-            None => {
+    let mut summary = SweepSummary::default();
+    // The roles of the authors, looked up once per guild
+    let mut roles = HashMap::new();
+    for (channel, rule) in channel_rules(config, &guild, &channels) {
+        let matched = match rule {
+            Ok(Some(matched)) => matched,
+            Ok(None) => {
                 info!(
                     "Skipping channel {} in guild {} as there is no configuration",
                     channel.name, guild.name
                 );
                 continue;
             }
+            Err(e) => {
+                summary.failed_channels += 1;
+                error!(
+                    "Skipping channel {} in guild {} as the configuration is invalid: {}",
+                    channel.name, guild.name, e
+                );
+                continue;
+            }
         };
 
-        match process_channel(client, &channel, *max_age, delete_pinned).await {
-            Ok(num) => info!(
-                "Deleted {} messages from {} in guild {}",
-                num, channel.name, guild.name
-            ),
-            Err(e) => error!(
-                "Could not process channel {} in guild {}: {:?}",
-                channel.name, guild.name, e
+        let labels = ChannelLabels::new(
+            *guild.id.as_u64(),
+            &guild.name,
+            *channel.id.as_u64(),
+            &channel.name,
+        );
+        if let Some(max_age) = matched.rule.max_age {
+            metrics::CHANNEL_RETENTION
+                .with_label_values(&labels.values())
+                .set(max_age.num_seconds() as f64);
+        }
+        if let Some(keep) = matched.rule.keep {
+            metrics::CHANNEL_KEEP
+                .with_label_values(&labels.values())
+                .set(keep as f64);
+        }
+
+        summary.channels += 1;
+        match process_channel(
+            client,
+            channel,
+            &matched,
+            config,
+            state,
+            rate_limits,
+            &mut roles,
+        )
+        .await
+        {
+            Ok(report) if config.dry_run => println!(
+                "[dry run] {} in guild {}: {}",
+                channel.name, guild.name, report
             ),
+            Ok(report) => {
+                summary.deleted += report.deleted;
+                summary.failed_deletions += report.failed;
+                metrics::MESSAGES_SCANNED
+                    .with_label_values(&labels.values())
+                    .inc_by(report.scanned);
+                metrics::MESSAGES_DELETED
+                    .with_label_values(&labels.values())
+                    .inc_by(report.deleted);
+                if report.failed > 0 {
+                    summary.failed_channels += 1;
+                    warn!(
+                        guild_id:% = guild.id, channel_id:% = channel.id,
+                        deleted = report.deleted, failed = report.failed;
+                        "Deleted {} messages from {} in guild {}, {} could not be deleted",
+                        report.deleted, channel.name, guild.name, report.failed
+                    )
+                } else {
+                    metrics::CHANNEL_LAST_SUCCESS
+                        .with_label_values(&labels.values())
+                        .set(Utc::now().timestamp() as f64);
+                    info!(
+                        guild_id:% = guild.id, channel_id:% = channel.id, deleted = report.deleted;
+                        "Deleted {} messages from {} in guild {}",
+                        report.deleted, channel.name, guild.name
+                    )
+                }
+            }
+            Err(e) if is_fatal(&e) => return Err(e),
+            Err(e) if Failure::find(&e) == Failure::Forbidden => {
+                summary.skipped_channels += 1;
+                warn!(
+                    guild_id:% = guild.id, channel_id:% = channel.id;
+                    "Skipping channel {} in guild {} as the bot lacks permissions, it needs \
+                     Read Message History and Manage Messages: {:#}",
+                    channel.name, guild.name, e
+                )
+            }
+            Err(e) => {
+                summary.failed_channels += 1;
+                error!(
+                    guild_id:% = guild.id, channel_id:% = channel.id;
+                    "Could not process channel {} in guild {}: {:?}",
+                    channel.name, guild.name, e
+                )
+            }
         };
     }
-    Ok(())
+
+    let waited = rate_limits.waited(Some(*guild.id.as_u64()));
+    if waited > StdDuration::from_secs(0) {
+        info!(
+            "Waited {:.1}s for rate limits in guild {}",
+            waited.as_secs_f64(),
+            guild.name
+        );
+    }
+    Ok(summary)
+}
+
+/// Summarizes which messages of a channel were (or in a dry run would have
+/// been) deleted.
+#[derive(Debug, Default)]
+pub struct ChannelReport {
+    /// Messages that were fetched.
+    pub scanned: u64,
+    /// Messages that are older than the retention and not excluded.
+    pub candidates: u64,
+    /// Messages that were actually deleted.
+    pub deleted: u64,
+    /// Messages that couldn't be archived or deleted.
+    pub failed: u64,
+    /// Messages that are older than the retention, but pinned.
+    pub pinned_excluded: u64,
+    /// Messages that are older than the retention, but exempt (see
+    /// [`Rule::is_exempt`]).
+    pub exempt_excluded: u64,
+    pub oldest_candidate: Option<DateTime<Utc>>,
+    pub newest_candidate: Option<DateTime<Utc>>,
+}
+
+impl ChannelReport {
+    fn add(&mut self, filtered: &FilteredMessages) {
+        self.candidates += filtered.candidates.len() as u64;
+        self.pinned_excluded += filtered.pinned_excluded;
+        self.exempt_excluded += filtered.exempt_excluded;
+        for msg in &filtered.candidates {
+            self.oldest_candidate = Some(
+                self.oldest_candidate
+                    .map_or(msg.timestamp, |oldest| oldest.min(msg.timestamp)),
+            );
+            self.newest_candidate = Some(
+                self.newest_candidate
+                    .map_or(msg.timestamp, |newest| newest.max(msg.timestamp)),
+            );
+        }
+    }
+}
+
+impl fmt::Display for ChannelReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "would delete {} messages", self.candidates)?;
+        if let (Some(oldest), Some(newest)) = (self.oldest_candidate, self.newest_candidate) {
+            write!(
+                f,
+                " (from {} to {})",
+                oldest.to_rfc3339(),
+                newest.to_rfc3339()
+            )?;
+        }
+        write!(f, ", {} pinned messages excluded", self.pinned_excluded)?;
+        if self.exempt_excluded > 0 {
+            write!(f, ", {} exempt messages excluded", self.exempt_excluded)?;
+        }
+        Ok(())
+    }
 }
 
-/// Gets all messages from a channel that are older than max_age and deletes
-/// them. Returns the number of messages deleted.
+/// Gets all messages from a channel that the rule expires and deletes them,
+/// unless it's a dry run. With an archive, messages are only deleted once
+/// they're archived. Without a number of messages to keep, fetching starts at
+/// the cutoff, so younger messages are never downloaded. Otherwise it starts
+/// at the newest message to count the ones that are kept. If the channel was
+/// swept with the same rule before, only the messages newer than that sweep are
+/// fetched, unless the last full scan is older than the full scan interval.
+/// `roles` caches the roles of the authors in the guild, if the rule needs them.
 async fn process_channel(
-    client: &Http,
+    client: &impl DiscordApi,
     channel: &GuildChannel,
-    max_age: Duration,
-    delete_pinned: bool,
-) -> Result<u64> {
-    let mut deletion_count = 0;
+    matched: &RuleMatch<'_>,
+    config: &Config,
+    state: &State,
+    rate_limits: &RateLimits,
+    roles: &mut HashMap<u64, Vec<u64>>,
+) -> Result<ChannelReport> {
+    let channel_id = *channel.id.as_u64();
+    let guild_id = *channel.guild_id.as_u64();
+    let route = format!("GET /channels/{}/messages", channel_id);
+    let started = Utc::now();
+    let keep = matched.rule.keep;
+    let delete_pinned = matched.rule.delete_pinned.unwrap_or(config.delete_pinned);
+    let dry_run = config.dry_run;
+    let archive_path = config
+        .archive_path
+        .as_deref()
+        .filter(|_| matched.rule.archive.unwrap_or(true));
+    let rule = rule_fingerprint(matched.rule, delete_pinned);
+    let previous = state.get(channel_id, &rule).filter(|channel_state| {
+        channel_state
+            .full_scan_at
+            .is_some_and(|full_scan_at| started - full_scan_at < config.full_scan_interval)
+    });
+    let swept_until = previous.as_ref().map(|previous| previous.swept_until);
+    // Only messages older than the shortest maximum age can expire, the ones
+    // older than the longest are decided for good
+    let cutoff_of =
+        |max_age: Option<Duration>| max_age.map_or(0, |max_age| snowflake_at(started - max_age));
+    let cutoff = cutoff_of(matched.rule.max_ages().max());
+    let mut report = ChannelReport::default();
 
-    let first_batch = client
-        .get_messages(*channel.id.as_u64(), "?limit=100")
-        .await
-        .context("Could not get messages")?;
-    deletion_count += delete_messages(
-        client,
-        channel,
-        filter_messages(&first_batch, max_age, delete_pinned),
-    )
-    .await
-    .context("Could not delete messages")?;
-
-    let mut oldest_msg_id = first_batch.last().map(|msg| *msg.id.as_u64());
-    while let Some(before_msg_id) = oldest_msg_id {
-        let batch = client
-            .get_messages(
-                *channel.id.as_u64(),
-                &format!("?limit=100&before={}", before_msg_id),
-            )
+    let mut before_msg_id = match keep {
+        Some(_) => None,
+        None => Some(cutoff_of(matched.rule.max_ages().min())),
+    };
+    // The number of messages counted towards `keep` so far and the oldest one
+    // kept by count
+    let mut position = 0;
+    let mut oldest_kept = None;
+    loop {
+        let batch = rate_limits
+            .retry(Some(guild_id), &route, || {
+                client.get_messages(channel_id, before_msg_id, 100)
+            })
             .await
             .context("Could not get messages")?;
-        deletion_count += delete_messages(
-            client,
-            channel,
-            filter_messages(&batch, max_age, delete_pinned),
-        )
-        .await
-        .context("Could not delete messages")?;
-        oldest_msg_id = batch.last().map(|msg| *msg.id.as_u64());
+        report.scanned += batch.len() as u64;
+
+        if matched.rule.uses_roles() {
+            get_roles(client, guild_id, &batch, rate_limits, roles).await?;
+        }
+        let filtered = filter_messages(&batch, matched.rule, delete_pinned, position, roles);
+        report.add(&filtered);
+        if let Some(msg) = filtered.oldest_kept {
+            oldest_kept = Some(*msg.id.as_u64());
+        }
+        position += filtered.counted;
+        if !dry_run {
+            let candidates = match archive_path {
+                Some(archive_path) => {
+                    let (downloaded, attachments) = if config.archive_attachments {
+                        download_attachments(client, archive_path, config, &filtered.candidates)
+                            .await
+                    } else {
+                        (filtered.candidates.clone(), HashMap::new())
+                    };
+                    let archived = archive_messages(
+                        archive_path,
+                        guild_id,
+                        channel_id,
+                        &downloaded,
+                        &attachments,
+                    );
+                    // The rest is tried again with the next run
+                    report.failed += (filtered.candidates.len() - archived.len()) as u64;
+                    archived
+                }
+                None => filtered.candidates,
+            };
+            delete_messages(
+                client,
+                channel,
+                rate_limits,
+                &mut report,
+                &candidates,
+                &matched.key,
+            )
+            .await
+            .context("Could not delete messages")?;
+        }
+
+        before_msg_id = match batch.last() {
+            Some(msg) if swept_until.is_none_or(|until| *msg.id.as_u64() > until) => {
+                Some(*msg.id.as_u64())
+            }
+            _ => break, // the rest was handled by a previous sweep
+        };
+    }
+
+    // Messages that failed to delete need to be fetched again next time.
+    if !dry_run && report.failed == 0 {
+        state.update(
+            channel_id,
+            ChannelState {
+                // Everything older than the oldest kept message was handled
+                swept_until: cutoff.max(oldest_kept.unwrap_or(0)),
+                rule,
+                full_scan_at: match previous {
+                    Some(previous) => previous.full_scan_at,
+                    None => Some(started),
+                },
+            },
+        );
+    }
+    Ok(report)
+}
+
+/// Identifies the options of a rule that decide which messages are kept.
+fn rule_fingerprint(rule: &Rule, delete_pinned: bool) -> String {
+    let mut fingerprint = String::new();
+    if let Some(max_age) = rule.max_age {
+        fingerprint += &format!("max_age={},", format_duration(max_age));
+    }
+    if let Some(keep) = rule.keep {
+        fingerprint += &format!("keep={},", keep);
+    }
+    for o in &rule.overrides {
+        fingerprint += &format!("override={}:{},", o.filter, format_duration(o.max_age));
+    }
+    if let Some(exempt) = &rule.exempt {
+        fingerprint += &format!("exempt={},", exempt);
     }
+    if let Some(only) = &rule.only {
+        fingerprint += &format!("only={},", only);
+    }
+    fingerprint + &format!("delete_pinned={}", delete_pinned)
+}
 
-    Ok(deletion_count)
+/// The messages of a batch that the rule expires.
+#[derive(Default)]
+struct FilteredMessages<'a> {
+    /// The messages that should be deleted.
+    candidates: Vec<&'a Message>,
+    /// The number of pinned messages that are kept.
+    pinned_excluded: u64,
+    /// The number of messages the rule exempts.
+    exempt_excluded: u64,
+    /// The number of messages that count towards the messages the rule keeps.
+    counted: u64,
+    /// The oldest message of the batch the rule keeps by count.
+    oldest_kept: Option<&'a Message>,
 }
 
-fn filter_messages(messages: &[Message], max_age: Duration, delete_pinned: bool) -> Vec<u64> {
+/// Picks the messages of a batch that are older than their maximum age (see
+/// [`Rule::max_age_of`]) or beyond the newest messages the rule keeps, unless
+/// they're exempt or pinned. Only messages the rule could delete count towards
+/// the ones it keeps. `position` is the number of those in the previous
+/// batches, `roles` are the roles of the authors.
+fn filter_messages<'a>(
+    messages: &'a [Message],
+    rule: &Rule,
+    delete_pinned: bool,
+    position: u64,
+    roles: &HashMap<u64, Vec<u64>>,
+) -> FilteredMessages<'a> {
     let now = Utc::now();
-    messages
-        .iter()
-        .filter(|msg| now.signed_duration_since(msg.timestamp) > max_age)
-        .filter(|msg| delete_pinned || !msg.pinned)
-        .map(|msg| *msg.id.as_u64())
-        .collect()
+    let mut filtered = FilteredMessages::default();
+    for msg in messages {
+        let author_roles = roles
+            .get(msg.author.id.as_u64())
+            .map_or(&[][..], Vec::as_slice);
+        let too_old = rule
+            .max_age_of(msg, author_roles)
+            .is_some_and(|max_age| now.signed_duration_since(msg.timestamp) > max_age);
+        let beyond_keep = rule
+            .keep
+            .is_some_and(|keep| position + filtered.counted >= keep);
+        let exempt = rule.is_exempt(msg, author_roles);
+        let kept_pinned = msg.pinned && !delete_pinned;
+        if !exempt && !kept_pinned {
+            filtered.counted += 1;
+            if rule.keep == Some(position + filtered.counted) {
+                filtered.oldest_kept = Some(msg);
+            }
+        }
+        if !too_old && !beyond_keep {
+            continue;
+        }
+        if exempt {
+            filtered.exempt_excluded += 1;
+        } else if kept_pinned {
+            filtered.pinned_excluded += 1;
+        } else {
+            filtered.candidates.push(msg);
+        }
+    }
+    filtered
+}
+
+/// Looks up the roles of the authors of the messages that aren't known yet.
+/// Webhooks and users that left the guild have none.
+async fn get_roles(
+    client: &impl DiscordApi,
+    guild_id: u64,
+    messages: &[Message],
+    rate_limits: &RateLimits,
+    roles: &mut HashMap<u64, Vec<u64>>,
+) -> Result<()> {
+    let route = format!("GET /guilds/{}/members", guild_id);
+    for msg in messages.iter().filter(|msg| msg.webhook_id.is_none()) {
+        let user_id = *msg.author.id.as_u64();
+        if roles.contains_key(&user_id) {
+            continue;
+        }
+        let res = rate_limits
+            .retry(Some(guild_id), &route, || {
+                client.get_member(guild_id, user_id)
+            })
+            .await;
+        let member_roles = match res {
+            Ok(member) => member.roles.iter().map(|role| *role.as_u64()).collect(),
+            Err(e) if Failure::of(&e) == Failure::UnknownMember => vec![],
+            Err(e) => return Err(e).context("Could not get member"),
+        };
+        roles.insert(user_id, member_roles);
+    }
+    Ok(())
 }
 
-/// Delete the messages with the given ids in the given channel. Returns the
-/// number of messages deleted.
+/// Delete the given messages in the given channel and counts them in the
+/// report. A message that can't be deleted is counted as failed, only missing
+/// permissions abort the channel. Every deletion is recorded as an audit event
+/// with the key of the rule that matched.
 async fn delete_messages(
-    client: &Http,
+    client: &impl DiscordApi,
     channel: &GuildChannel,
-    message_ids: Vec<u64>,
-) -> Result<u64> {
-    for msg_id in &message_ids {
-        client
-            .delete_message(*channel.id.as_u64(), *msg_id)
-            .await
-            .context("Could not delete message")?;
+    rate_limits: &RateLimits,
+    report: &mut ChannelReport,
+    messages: &[&Message],
+    rule: &str,
+) -> Result<()> {
+    let channel_id = *channel.id.as_u64();
+    let guild_id = Some(*channel.guild_id.as_u64());
+    let bulk_route = format!("POST /channels/{}/messages/bulk-delete", channel_id);
+    let single_route = format!("DELETE /channels/{}/messages", channel_id);
+    let messages: HashMap<u64, &Message> = messages
+        .iter()
+        .map(|msg| (*msg.id.as_u64(), *msg))
+        .collect();
+    let (bulk_batches, mut single_ids) =
+        plan_deletions(messages.keys().copied().collect(), Utc::now());
+
+    for batch in bulk_batches {
+        let body = json!({ "messages": batch });
+        let res = rate_limits
+            .retry(guild_id, &bulk_route, || {
+                client.delete_messages(channel_id, &body)
+            })
+            .await;
+        match res {
+            Ok(()) => {
+                report.deleted += batch.len() as u64;
+                for msg_id in &batch {
+                    audit_deletion(channel, messages[msg_id], rule, false);
+                }
+            }
+            Err(e) if Failure::of(&e).aborts_channel() => {
+                return Err(e).context("Could not bulk delete messages")
+            }
+            Err(e) => {
+                // Find out which messages are the problem by deleting them
+                // one by one.
+                warn!(
+                    guild_id:% = channel.guild_id, channel_id:% = channel.id;
+                    "Could not bulk delete messages in {}, deleting them one by one: {}",
+                    channel.name, e
+                );
+                single_ids.extend(batch);
+            }
+        }
+    }
+
+    for msg_id in single_ids {
+        let res = rate_limits
+            .retry(guild_id, &single_route, || {
+                client.delete_message(channel_id, msg_id)
+            })
+            .await;
+        match res.as_ref().map_err(Failure::of) {
+            Ok(()) => {
+                report.deleted += 1;
+                audit_deletion(channel, messages[&msg_id], rule, false);
+            }
+            Err(Failure::UnknownMessage) => {
+                report.deleted += 1;
+                audit_deletion(channel, messages[&msg_id], rule, true);
+            }
+            Err(Failure::Forbidden) | Err(Failure::Unauthorized) => {
+                return res.context("Could not delete message")
+            }
+            Err(Failure::UnknownMember) | Err(Failure::Other) => {
+                report.failed += 1;
+                warn!(
+                    guild_id:% = channel.guild_id, channel_id:% = channel.id, message_id:% = msg_id;
+                    "Could not delete message {} in {}: {}",
+                    msg_id,
+                    channel.name,
+                    res.unwrap_err()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the deletion of a message as an audit event. `already_deleted`
+/// means someone else deleted it before the bot got to it.
+fn audit_deletion(channel: &GuildChannel, msg: &Message, rule: &str, already_deleted: bool) {
+    info!(
+        target: AUDIT_TARGET,
+        guild_id:% = channel.guild_id,
+        channel_id:% = channel.id,
+        message_id:% = msg.id,
+        author_id:% = msg.author.id,
+        timestamp = msg.timestamp.to_rfc3339(),
+        pinned = msg.pinned,
+        rule = rule,
+        already_deleted = already_deleted;
+        "Deleted message {} by {} from {}",
+        msg.id,
+        msg.author.id,
+        channel.name
+    );
+}
+
+/// Splits the given message ids into batches for the Bulk Delete Messages
+/// endpoint and the ids that need to be deleted one by one, because they're
+/// too old or would end up alone in a batch.
+fn plan_deletions(message_ids: Vec<u64>, now: DateTime<Utc>) -> (Vec<Vec<u64>>, Vec<u64>) {
+    let (bulk_ids, mut single_ids): (Vec<u64>, Vec<u64>) =
+        message_ids.into_iter().partition(|id| {
+            now.signed_duration_since(MessageId(*id).created_at()) < bulk_delete_max_age()
+        });
+
+    let mut bulk_batches = vec![];
+    for batch in bulk_ids.chunks(BULK_DELETE_MAX_MESSAGES) {
+        if batch.len() < BULK_DELETE_MIN_MESSAGES {
+            single_ids.extend_from_slice(batch);
+        } else {
+            bulk_batches.push(batch.to_vec());
+        }
     }
-    Ok(message_ids.len() as u64)
+
+    (bulk_batches, single_ids)
 }
 
 #[cfg(test)]
 mod tests {
-    use chrono::Duration;
-    use dotenv::dotenv;
-    use rand::Rng;
-    use serenity::{
-        client::{validate_token, Client},
-        model::{channel::GuildChannel, id::MessageId},
-        utils::MessageBuilder,
-    };
-    use std::{env, thread};
-    use tokio::runtime::Runtime;
+    use chrono::{Duration, TimeZone};
+    use serenity::{http::StatusCode, model::id::MessageId};
 
     use super::*;
+    use crate::{
+        config::{parse_channel_retention, MessageFilter, Override},
+        discord::{
+            fake::{self, discord_error, FakeDiscord},
+            stand_in::StandIn,
+        },
+    };
+
+    /// Adds a channel with two old messages, the latter pinned, and a new
+    /// one. Returns the ids of the messages.
+    fn integration_channel(discord: &FakeDiscord) -> (u64, [u64; 3]) {
+        discord.add_guild(1, "guild");
+        discord.add_channel(1, 10, "integration");
+        let now = Utc::now();
+        let foo = discord.add_message(10, now - Duration::seconds(4), false);
+        let bar = discord.add_message(10, now - Duration::seconds(4), true);
+        let baz = discord.add_message(10, now, false);
+        (10, [foo, bar, baz])
+    }
+
+    #[tokio::test]
+    async fn test_integration_simple() -> Result<()> {
+        let stand_in = StandIn::start();
+        let (channel_id, [_foo, bar, baz]) = integration_channel(&stand_in.discord);
+
+        let mut config = Config::default();
+        config
+            .retention
+            .insert(None, "integration", Duration::seconds(2).into())?;
+        let client = crate::discord::Client::new("token", Some(&stand_in.url));
+        run(&client, &config, &State::default()).await?;
+
+        // Only the pinned and the newer message are left
+        assert_eq!(stand_in.discord.messages(channel_id), vec![bar, baz]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_integration_pinned() -> Result<()> {
+        let stand_in = StandIn::start();
+        let (channel_id, [_foo, _bar, baz]) = integration_channel(&stand_in.discord);
+
+        let mut config = Config {
+            delete_pinned: true,
+            ..Config::default()
+        };
+        config
+            .retention
+            .insert(None, "integration", Duration::seconds(2).into())?;
+        let client = crate::discord::Client::new("token", Some(&stand_in.url));
+        run(&client, &config, &State::default()).await?;
+
+        // The pinned message was deleted as well
+        assert_eq!(stand_in.discord.messages(channel_id), vec![baz]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failure() {
+        let unknown_message = discord_error(StatusCode::NOT_FOUND, UNKNOWN_MESSAGE);
+        assert_eq!(Failure::of(&unknown_message), Failure::UnknownMessage);
+        let forbidden = discord_error(StatusCode::FORBIDDEN, MISSING_PERMISSIONS);
+        assert_eq!(Failure::of(&forbidden), Failure::Forbidden);
+        let server_error = discord_error(StatusCode::BAD_GATEWAY, 0);
+        assert_eq!(Failure::of(&server_error), Failure::Other);
+        let unauthorized = discord_error(StatusCode::UNAUTHORIZED, 0);
+        assert!(is_fatal(&anyhow::Error::new(unauthorized)));
+
+        let err = Err::<(), _>(forbidden)
+            .context("Could not get messages")
+            .unwrap_err();
+        assert_eq!(Failure::find(&err), Failure::Forbidden);
+        assert_eq!(Failure::find(&anyhow::anyhow!("foo")), Failure::Other);
+    }
+
+    #[test]
+    fn test_snowflake_at() {
+        let time = Utc.timestamp_millis_opt(1_600_000_000_123).unwrap();
+        assert_eq!(MessageId(snowflake_at(time)).created_at(), time);
+        assert_eq!(snowflake_at(Utc.timestamp_millis_opt(0).unwrap()), 0);
+    }
+
+    #[test]
+    fn test_plan_deletions_splits_by_age() {
+        let now = Utc::now();
+        let young = snowflake_at(now - Duration::days(1));
+        let younger = snowflake_at(now - Duration::hours(1));
+        let old = snowflake_at(now - Duration::weeks(3));
+
+        let (bulk_batches, single_ids) = plan_deletions(vec![young, old, younger], now);
+        assert_eq!(bulk_batches, vec![vec![young, younger]]);
+        assert_eq!(single_ids, vec![old]);
+    }
+
+    #[test]
+    fn test_plan_deletions_batches() {
+        let now = Utc::now();
+        let message_ids: Vec<u64> = (0..201)
+            .map(|i| snowflake_at(now - Duration::minutes(i)))
+            .collect();
+
+        let (bulk_batches, single_ids) = plan_deletions(message_ids.clone(), now);
+        assert_eq!(bulk_batches.len(), 2);
+        assert_eq!(bulk_batches[0], message_ids[0..100].to_vec());
+        assert_eq!(bulk_batches[1], message_ids[100..200].to_vec());
+        assert_eq!(single_ids, vec![message_ids[200]]);
+    }
+
+    #[test]
+    fn test_plan_deletions_single_young_message() {
+        let now = Utc::now();
+        let young = snowflake_at(now - Duration::days(1));
+
+        let (bulk_batches, single_ids) = plan_deletions(vec![young], now);
+        assert!(bulk_batches.is_empty());
+        assert_eq!(single_ids, vec![young]);
+    }
+
+    #[test]
+    fn test_filter_messages() {
+        let now = Utc::now();
+        let message = |time, pinned| fake::message(snowflake_at(time), 1, time, pinned);
+        let messages = vec![
+            message(now - Duration::hours(1), false),
+            message(now - Duration::days(2), true),
+            message(now - Duration::days(3), false),
+            message(now - Duration::days(4), false),
+        ];
+
+        let filtered = filter_messages(
+            &messages,
+            &Duration::days(1).into(),
+            false,
+            0,
+            &HashMap::new(),
+        );
+        assert_eq!(filtered.candidates.len(), 2);
+        assert_eq!(filtered.pinned_excluded, 1);
+
+        let filtered = filter_messages(
+            &messages,
+            &Duration::days(1).into(),
+            true,
+            0,
+            &HashMap::new(),
+        );
+        assert_eq!(filtered.candidates.len(), 3);
+        assert_eq!(filtered.pinned_excluded, 0);
+    }
+
+    fn ids(messages: &[&Message]) -> Vec<MessageId> {
+        messages.iter().map(|msg| msg.id).collect()
+    }
+
+    #[test]
+    fn test_filter_messages_keep() {
+        let now = Utc::now();
+        let message = |time, pinned| fake::message(snowflake_at(time), 1, time, pinned);
+        let messages = vec![
+            message(now - Duration::hours(1), false),
+            message(now - Duration::hours(2), true),
+            message(now - Duration::hours(3), false),
+            message(now - Duration::days(3), false),
+        ];
+        let keep = |keep| Rule {
+            keep: Some(keep),
+            ..Rule::default()
+        };
+
+        let filtered = filter_messages(&messages, &keep(1), false, 0, &HashMap::new());
+        assert_eq!(
+            ids(&filtered.candidates),
+            vec![messages[2].id, messages[3].id]
+        );
+        assert_eq!(filtered.pinned_excluded, 1);
+        assert_eq!(filtered.counted, 3);
+        assert_eq!(filtered.oldest_kept.map(|msg| msg.id), Some(messages[0].id));
+
+        // Newer messages of previous batches count too, pinned ones don't
+        let filtered = filter_messages(&messages, &keep(4), false, 2, &HashMap::new());
+        assert_eq!(ids(&filtered.candidates), vec![messages[3].id]);
+
+        // Either expires a message
+        let rule = Rule {
+            max_age: Some(Duration::days(1)),
+            ..keep(10)
+        };
+        let filtered = filter_messages(&messages, &rule, false, 0, &HashMap::new());
+        assert_eq!(ids(&filtered.candidates), vec![messages[3].id]);
+    }
+
+    #[test]
+    fn test_filter_messages_keep_only() {
+        let now = Utc::now();
+        let message = |id, bot| {
+            fake::message_with(
+                id,
+                1,
+                now - Duration::minutes(id as i64),
+                json!({ "author": fake::author(if bot { 2 } else { 3 }, bot) }),
+            )
+        };
+        let messages = vec![
+            message(1, false),
+            message(2, true),
+            message(3, false),
+            message(4, true),
+            message(5, true),
+            message(6, false),
+        ];
+        let rule = Rule {
+            keep: Some(2),
+            only: Some(MessageFilter {
+                bots: true,
+                ..MessageFilter::default()
+            }),
+            ..Rule::default()
+        };
+
+        // Only the messages the rule selects count, the newest two of the bot
+        // are kept
+        let filtered = filter_messages(&messages, &rule, false, 0, &HashMap::new());
+        assert_eq!(ids(&filtered.candidates), vec![messages[4].id]);
+        assert_eq!(filtered.exempt_excluded, 1);
+        assert_eq!(filtered.counted, 3);
+        assert_eq!(filtered.oldest_kept.map(|msg| msg.id), Some(messages[3].id));
+    }
+
+    #[test]
+    fn test_filter_messages_authors() {
+        let now = Utc::now();
+        let message = |id, days, author| {
+            fake::message_with(
+                id,
+                1,
+                now - Duration::days(days),
+                json!({ "author": author }),
+            )
+        };
+        let messages = vec![
+            message(1, 2, fake::author(2, true)),
+            message(2, 2, fake::author(3, false)),
+            message(3, 5, fake::author(3, false)),
+            message(4, 5, fake::author(4, false)),
+        ];
+        let rule = Rule {
+            max_age: Some(Duration::days(4)),
+            overrides: vec![Override {
+                filter: MessageFilter {
+                    bots: true,
+                    ..MessageFilter::default()
+                },
+                max_age: Duration::days(1),
+            }],
+            exempt: Some(MessageFilter {
+                roles: vec![42],
+                ..MessageFilter::default()
+            }),
+            ..Rule::default()
+        };
+        let roles = HashMap::from([(4, vec![42])]);
+
+        let filtered = filter_messages(&messages, &rule, false, 0, &roles);
+        assert_eq!(
+            ids(&filtered.candidates),
+            vec![messages[0].id, messages[2].id]
+        );
+        assert_eq!(filtered.exempt_excluded, 1);
+        assert_eq!(filtered.pinned_excluded, 0);
+    }
+
+    #[test]
+    fn test_filter_messages_content() {
+        let now = Utc::now();
+        let message =
+            |id, days, fields| fake::message_with(id, 1, now - Duration::days(days), fields);
+        let attachment = json!([fake::attachment(4, 1, "foo.png", 3)]);
+        let star = json!([{ "count": 3, "me": false, "emoji": { "id": null, "name": "⭐" } }]);
+        let embeds = json!([{ "type": "rich", "title": "Deployed" }]);
+        let messages = vec![
+            message(1, 2, json!({ "content": "", "embeds": embeds })),
+            message(2, 2, json!({ "content": "[auto] nightly build" })),
+            message(
+                3,
+                10,
+                json!({ "content": "[auto] screenshot", "attachments": attachment }),
+            ),
+            message(
+                4,
+                40,
+                json!({ "content": "[auto] screenshot", "attachments": attachment }),
+            ),
+            message(
+                5,
+                10,
+                json!({ "content": "[auto] release", "reactions": star }),
+            ),
+            message(6, 10, json!({ "content": "hand-written" })),
+            message(7, 10, json!({ "content": "[auto] nightly build" })),
+        ];
+        let rule = Rule {
+            max_age: Some(Duration::weeks(1)),
+            overrides: vec![
+                Override {
+                    filter: MessageFilter {
+                        attachments: true,
+                        ..MessageFilter::default()
+                    },
+                    max_age: Duration::weeks(4),
+                },
+                Override {
+                    filter: MessageFilter {
+                        embeds_only: true,
+                        ..MessageFilter::default()
+                    },
+                    max_age: Duration::days(1),
+                },
+            ],
+            exempt: Some(MessageFilter {
+                reactions: vec!["⭐".to_string(), "📌".to_string()],
+                ..MessageFilter::default()
+            }),
+            only: Some(MessageFilter {
+                content: Some(regex::Regex::new(r"^\[auto\]").unwrap()),
+                embeds_only: true,
+                ..MessageFilter::default()
+            }),
+            ..Rule::default()
+        };
+
+        let filtered = filter_messages(&messages, &rule, false, 0, &HashMap::new());
+        // Embeds only after a day, attachments after four weeks, the rest after
+        // a week unless starred or hand-written
+        assert_eq!(
+            ids(&filtered.candidates),
+            vec![messages[0].id, messages[3].id, messages[6].id]
+        );
+        assert_eq!(filtered.exempt_excluded, 2);
+    }
+
+    #[test]
+    fn test_channel_report() {
+        let now = Utc::now();
+        let message = |time, pinned| fake::message(snowflake_at(time), 1, time, pinned);
+        let first_batch = vec![
+            message(now - Duration::days(2), false),
+            message(now - Duration::days(3), true),
+        ];
+        let second_batch = vec![message(now - Duration::days(5), false)];
+
+        let mut report = ChannelReport::default();
+        let rule = Rule::from(Duration::days(1));
+        report.add(&filter_messages(
+            &first_batch,
+            &rule,
+            false,
+            0,
+            &HashMap::new(),
+        ));
+        report.add(&filter_messages(
+            &second_batch,
+            &rule,
+            false,
+            2,
+            &HashMap::new(),
+        ));
+        assert_eq!(report.candidates, 2);
+        assert_eq!(report.deleted, 0);
+        assert_eq!(report.pinned_excluded, 1);
+        assert_eq!(report.oldest_candidate, Some(second_batch[0].timestamp));
+        assert_eq!(report.newest_candidate, Some(first_batch[0].timestamp));
+    }
+
+    fn config(channel_retention: &str) -> Config {
+        Config {
+            retention: parse_channel_retention(channel_retention.to_string()).unwrap(),
+            ..Config::default()
+        }
+    }
+
+    fn rate_limits() -> RateLimits {
+        RateLimits::new(StdDuration::from_secs(60))
+    }
+
+    fn count_requests(discord: &FakeDiscord, prefix: &str) -> usize {
+        discord
+            .requests()
+            .iter()
+            .filter(|request| request.starts_with(prefix))
+            .count()
+    }
 
-    const CHANNEL_NAME_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
-
-    macro_rules! integration_test {
-        ($test_name:ident, $test_func:expr) => {
-            #[test]
-            #[ignore]
-            fn $test_name() {
-                dotenv().ok();
-                let discord_token = env::var("INTEGRATION_DISCORD_TOKEN")
-                    .expect("INTEGRATION_DISCORD_TOKEN is unset");
-                validate_token(&discord_token).expect("Token is invalid");
-
-                Runtime::new().unwrap().block_on(async {
-                    let client = Client::builder(&discord_token).await.unwrap();
-                    let http_client = &client.cache_and_http.http;
-
-                    // Setup
-                    let guilds = http_client
-                        .get_guilds(&GuildPagination::After(GuildId(0)), 1)
-                        .await
-                        .expect("Could not get guilds");
-                    let guild = guilds.first().expect("Need at least one guild");
-
-                    let mut rng = rand::thread_rng();
-                    let channel_name: String = (0..16)
-                        .map(|_| {
-                            let idx = rng.gen_range(0, CHANNEL_NAME_CHARSET.len());
-                            CHANNEL_NAME_CHARSET[idx] as char
-                        })
-                        .collect();
-                    let channel = guild
-                        .id
-                        .create_channel(&http_client, |c| {
-                            c.name(&channel_name).kind(ChannelType::Text)
-                        })
-                        .await
-                        .unwrap();
-
-                    // Run test closure
-                    let (client, channel) = $test_func(client, channel).await.unwrap();
-
-                    // Teardown
-                    client
-                        .cache_and_http
-                        .http
-                        .delete_channel(*channel.id.as_u64())
-                        .await
-                        .expect("Could not delete channel");
-                });
+    #[tokio::test]
+    async fn test_run() {
+        let discord = FakeDiscord::default();
+        discord.add_guild(1, "guild");
+        discord.add_guild(2, "other guild");
+        discord.add_channel(1, 10, "general");
+        discord.add_channel(1, 11, "random");
+        discord.add_channel(2, 20, "general");
+        let now = Utc::now();
+        for channel_id in &[10, 11, 20] {
+            for hours in 0..250 {
+                let time = now - Duration::hours(hours) - Duration::minutes(30);
+                discord.add_message(*channel_id, time, false);
             }
+        }
+        let pinned = discord.add_message(10, now - Duration::days(30), true);
+
+        let summary = run(&discord, &config("guild#general:1d"), &State::default())
+            .await
+            .unwrap();
+        assert!(summary.is_success());
+        assert_eq!(summary.guilds, 2);
+        assert_eq!(summary.channels, 1);
+        assert_eq!(summary.deleted, 226);
+        let left = discord.messages(10);
+        assert_eq!(left.len(), 25);
+        assert_eq!(left[0], pinned);
+        assert_eq!(discord.messages(11).len(), 250);
+        assert_eq!(discord.messages(20).len(), 250);
+
+        // Three pages of expired messages and an empty one
+        assert_eq!(count_requests(&discord, "GET /channels/10/messages"), 4);
+        assert_eq!(count_requests(&discord, "POST /channels/10/"), 3);
+        assert_eq!(count_requests(&discord, "DELETE"), 0);
+        assert_eq!(count_requests(&discord, "GET /channels/11/"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_rate_limited() {
+        let discord = FakeDiscord::default();
+        discord.add_guild(1, "guild");
+        discord.add_channel(1, 10, "general");
+        discord.add_message(10, Utc::now() - Duration::days(2), false);
+        discord.rate_limit(3);
+
+        let summary = run(&discord, &config("general:1d"), &State::default())
+            .await
+            .unwrap();
+        assert!(summary.is_success());
+        assert_eq!(summary.deleted, 1);
+        // Three rate limited requests, the first page and an empty one
+        assert_eq!(count_requests(&discord, "GET /users/@me/guilds"), 5);
+        assert!(summary.waited >= fake::RETRY_AFTER * 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_all_guilds() {
+        let discord = FakeDiscord::default();
+        for id in 1..=150 {
+            discord.add_guild(id, &format!("guild {}", id));
+        }
+
+        let guilds = get_all_guilds(&discord, &rate_limits()).await.unwrap();
+        assert_eq!(guilds.len(), 150);
+        assert_eq!(count_requests(&discord, "GET /users/@me/guilds"), 3);
+    }
+
+    #[tokio::test]
+    async fn test_process_guild_skips_forbidden_channels() {
+        let discord = FakeDiscord::default();
+        let guild = discord.add_guild(1, "guild");
+        let old = Utc::now() - Duration::days(2);
+        for (channel_id, name) in &[(10, "general"), (11, "random"), (12, "log")] {
+            discord.add_channel(1, *channel_id, name);
+            discord.add_message(*channel_id, old, false);
+        }
+        discord.hide_channel(10);
+        discord.make_read_only(11);
+
+        let summary = process_guild(
+            &discord,
+            guild,
+            &config("*:1d"),
+            &State::default(),
+            &rate_limits(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.channels, 3);
+        assert_eq!(summary.skipped_channels, 2);
+        assert_eq!(summary.failed_channels, 0);
+        assert_eq!(summary.deleted, 1);
+        assert!(discord.messages(12).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_channel_swept_until() {
+        let discord = FakeDiscord::default();
+        discord.add_guild(1, "guild");
+        let channel = discord.add_channel(1, 10, "general");
+        let now = Utc::now();
+        for minutes in 0..150 {
+            discord.add_message(
+                10,
+                now - Duration::days(2) - Duration::minutes(minutes),
+                true,
+            );
+        }
+        let mut config = config("general:1d");
+        let rule = Rule::from(Duration::days(1));
+        let matched = RuleMatch {
+            key: "general".to_string(),
+            rule: &rule,
         };
+        let state = State::default();
+        let rate_limits = rate_limits();
+        let fetches = |discord: &FakeDiscord| count_requests(discord, "GET /channels/10/messages");
+
+        let report = process_channel(
+            &discord,
+            &channel,
+            &matched,
+            &config,
+            &state,
+            &rate_limits,
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.scanned, 150);
+        assert_eq!(report.pinned_excluded, 150);
+        assert_eq!(fetches(&discord), 3);
+
+        // The next run stops at the messages the first one swept
+        let report = process_channel(
+            &discord,
+            &channel,
+            &matched,
+            &config,
+            &state,
+            &rate_limits,
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.scanned, 100);
+        assert_eq!(fetches(&discord), 4);
+
+        // Unless a full scan is due
+        config.full_scan_interval = Duration::zero();
+        let report = process_channel(
+            &discord,
+            &channel,
+            &matched,
+            &config,
+            &state,
+            &rate_limits,
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.scanned, 150);
+        assert_eq!(fetches(&discord), 7);
     }
 
-    integration_test!(
-        test_integration_simple,
-        async move |client: Client, channel: GuildChannel| -> Result<(Client, GuildChannel)> {
-            let http_client = &client.cache_and_http.http;
-
-            // Add two messages and pin the latter
-            let _foo_msg = channel
-                .id
-                .say(&http_client, MessageBuilder::new().push("foo").build())
-                .await?;
-            let bar_msg = channel
-                .id
-                .say(&http_client, MessageBuilder::new().push("bar").build())
-                .await?;
-            bar_msg.pin(&client.cache_and_http).await?;
-
-            // Sleep
-            thread::sleep(Duration::seconds(4).to_std()?);
-
-            // Add third message
-            let _baz_msg = channel
-                .id
-                .say(&http_client, MessageBuilder::new().push("baz").build())
-                .await?;
-
-            // Process channel
-            let mut channel_retention = HashMap::new();
-            channel_retention.insert(channel.name.clone(), Duration::seconds(2));
-            run(&http_client, &channel_retention, false).await?;
-
-            // Assert we only have one message (the pinned one)
-            let messages = channel
-                .id
-                .messages(&http_client, |retriever| {
-                    retriever.after(MessageId(0)).limit(2)
-                })
-                .await?;
-            assert_eq!(2, messages.len()); // pinned + newer
-
-            Ok((client, channel))
+    #[tokio::test]
+    async fn test_process_channel_keep() {
+        let discord = FakeDiscord::default();
+        discord.add_guild(1, "guild");
+        let channel = discord.add_channel(1, 10, "ci-alerts");
+        let now = Utc::now();
+        for minutes in (0..150).rev() {
+            discord.add_message(10, now - Duration::minutes(minutes), false);
         }
-    );
+        let config = config("ci-alerts:keep=120");
+        let rule = Rule {
+            keep: Some(120),
+            ..Rule::default()
+        };
+        let matched = RuleMatch {
+            key: "ci-alerts".to_string(),
+            rule: &rule,
+        };
+        let state = State::default();
+        let rate_limits = rate_limits();
+        let newest = discord.messages(10).split_off(30);
+
+        // Fetching starts at the newest message, none is old enough for a cutoff
+        let report = process_channel(
+            &discord,
+            &channel,
+            &matched,
+            &config,
+            &state,
+            &rate_limits,
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.scanned, 150);
+        assert_eq!(report.deleted, 30);
+        assert_eq!(discord.messages(10), newest);
+
+        // The next run only fetches down to the oldest message it kept
+        let newer = discord.add_message(10, Utc::now(), false);
+        let report = process_channel(
+            &discord,
+            &channel,
+            &matched,
+            &config,
+            &state,
+            &rate_limits,
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.scanned, 121);
+        assert_eq!(report.deleted, 1);
+        assert_eq!(discord.messages(10).len(), 120);
+        assert_eq!(discord.messages(10).last(), Some(&newer));
+    }
+
+    #[tokio::test]
+    async fn test_process_channel_max_age_or_keep() {
+        let discord = FakeDiscord::default();
+        discord.add_guild(1, "guild");
+        let channel = discord.add_channel(1, 10, "bot-spam");
+        let now = Utc::now();
+        let old = discord.add_message(10, now - Duration::days(8), false);
+        let young: Vec<u64> = (0..5)
+            .map(|minutes| discord.add_message(10, now - Duration::minutes(minutes), false))
+            .collect();
+        let config = config("bot-spam:7d|keep=3");
+        let rule = Rule {
+            max_age: Some(Duration::days(7)),
+            keep: Some(3),
+            ..Rule::default()
+        };
+        let matched = RuleMatch {
+            key: "bot-spam".to_string(),
+            rule: &rule,
+        };
+
+        let report = process_channel(
+            &discord,
+            &channel,
+            &matched,
+            &config,
+            &State::default(),
+            &rate_limits(),
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.deleted, 3);
+        assert!(!discord.messages(10).contains(&old));
+        assert_eq!(discord.messages(10), vec![young[2], young[1], young[0]]);
+    }
 
-    integration_test!(
-        test_integration_pinned,
-        async move |client: Client, channel: GuildChannel| -> Result<(Client, GuildChannel)> {
-            let http_client = &client.cache_and_http.http;
-
-            // Add two messages and pin the latter
-            let _foo_msg = channel
-                .id
-                .say(&http_client, MessageBuilder::new().push("foo").build())
-                .await?;
-            let bar_msg = channel
-                .id
-                .say(&http_client, MessageBuilder::new().push("bar").build())
-                .await?;
-            bar_msg.pin(&client.cache_and_http).await?;
-
-            // Sleep
-            thread::sleep(Duration::seconds(4).to_std()?);
-
-            // Add third message
-            let _baz_msg = channel
-                .id
-                .say(&http_client, MessageBuilder::new().push("baz").build())
-                .await?;
-
-            // Process channel
-            let mut channel_retention = HashMap::new();
-            channel_retention.insert(channel.name.clone(), Duration::seconds(2));
-            run(&http_client, &channel_retention, true).await?;
-
-            // Assert we have one message (i.e. the pinned one was deleted as
-            // well)
-            let messages = channel
-                .id
-                .messages(&http_client, |retriever| {
-                    retriever.after(MessageId(0)).limit(2)
-                })
-                .await?;
-            assert_eq!(1, messages.len()); // pinned + newer
-
-            Ok((client, channel))
+    #[tokio::test]
+    async fn test_process_channel_authors() {
+        let discord = FakeDiscord::default();
+        discord.add_guild(1, "guild");
+        let channel = discord.add_channel(1, 10, "general");
+        discord.add_member(1, 2, &[]);
+        discord.add_member(1, 3, &[42]);
+        let now = Utc::now();
+        let add = |days, author| {
+            let fields = json!({ "author": author });
+            discord.add_message_with(10, now - Duration::days(days), fields)
+        };
+        let _old_bot = add(2, fake::author(5, true));
+        let young_human = add(2, fake::author(2, false));
+        let _old_human = add(30, fake::author(2, false));
+        let old_moderator = add(30, fake::author(3, false));
+        let _gone = add(30, fake::author(4, false));
+        let webhook = json!({ "author": fake::author(6, true), "webhook_id": "6" });
+        let _old_webhook = discord.add_message_with(10, now - Duration::days(2), webhook);
+        let config = Config::default();
+        let rule = Rule {
+            max_age: Some(Duration::weeks(4)),
+            overrides: vec![Override {
+                filter: MessageFilter {
+                    bots: true,
+                    webhooks: true,
+                    ..MessageFilter::default()
+                },
+                max_age: Duration::days(1),
+            }],
+            exempt: Some(MessageFilter {
+                roles: vec![42],
+                ..MessageFilter::default()
+            }),
+            ..Rule::default()
+        };
+        let matched = RuleMatch {
+            key: "general".to_string(),
+            rule: &rule,
+        };
+
+        let report = process_channel(
+            &discord,
+            &channel,
+            &matched,
+            &config,
+            &State::default(),
+            &rate_limits(),
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.deleted, 4);
+        assert_eq!(report.exempt_excluded, 1);
+        assert_eq!(discord.messages(10), vec![old_moderator, young_human]);
+        // Each author is looked up once, except for the webhook
+        assert_eq!(count_requests(&discord, "GET /guilds/1/members"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_process_guild_looks_up_authors_once() {
+        let discord = FakeDiscord::default();
+        let guild = discord.add_guild(1, "guild");
+        discord.add_member(1, 2, &[]);
+        discord.add_member(1, 3, &[42]);
+        let old = Utc::now() - Duration::days(2);
+        for channel_id in &[10, 11] {
+            discord.add_channel(1, *channel_id, &format!("channel-{}", channel_id));
+            for author_id in &[2, 3] {
+                let fields = json!({ "author": fake::author(*author_id, false) });
+                discord.add_message_with(*channel_id, old, fields);
+            }
         }
-    );
+        let mut config = Config::default();
+        let rule = Rule {
+            max_age: Some(Duration::days(1)),
+            exempt: Some(MessageFilter {
+                roles: vec![42],
+                ..MessageFilter::default()
+            }),
+            ..Rule::default()
+        };
+        config.retention.insert(None, "*", rule).unwrap();
+
+        let summary = process_guild(&discord, guild, &config, &State::default(), &rate_limits())
+            .await
+            .unwrap();
+        assert_eq!(summary.channels, 2);
+        assert_eq!(summary.deleted, 2);
+        assert_eq!(discord.messages(10).len(), 1);
+        assert_eq!(discord.messages(11).len(), 1);
+        // The roles of each author are looked up once for all channels
+        assert_eq!(count_requests(&discord, "GET /guilds/1/members/2"), 1);
+        assert_eq!(count_requests(&discord, "GET /guilds/1/members/3"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_delete_messages_falls_back_to_single_deletes() {
+        let discord = FakeDiscord::default();
+        let channel = discord.add_channel(1, 10, "general");
+        let time = Utc::now() - Duration::days(1);
+        let messages: Vec<Message> = (0..3)
+            .map(|_| fake::message(discord.add_message(10, time, false), 10, time, false))
+            .collect();
+        discord.reject_bulk_deletes();
+
+        let mut report = ChannelReport::default();
+        let candidates: Vec<&Message> = messages.iter().collect();
+        delete_messages(
+            &discord,
+            &channel,
+            &rate_limits(),
+            &mut report,
+            &candidates,
+            "general",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.deleted, 3);
+        assert_eq!(report.failed, 0);
+        assert!(discord.messages(10).is_empty());
+        assert_eq!(count_requests(&discord, "POST"), 1);
+        assert_eq!(count_requests(&discord, "DELETE"), 3);
+    }
+
+    #[tokio::test]
+    async fn test_delete_messages_unknown_message() {
+        let discord = FakeDiscord::default();
+        let channel = discord.add_channel(1, 10, "general");
+        let time = Utc::now() - Duration::weeks(3);
+        let gone = fake::message(snowflake_at(time), 10, time, false);
+
+        let mut report = ChannelReport::default();
+        delete_messages(
+            &discord,
+            &channel,
+            &rate_limits(),
+            &mut report,
+            &[&gone],
+            "general",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.deleted, 1);
+        assert_eq!(report.failed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_delete_messages_forbidden() {
+        let discord = FakeDiscord::default();
+        let channel = discord.add_channel(1, 10, "general");
+        let time = Utc::now() - Duration::weeks(3);
+        let id = discord.add_message(10, time, false);
+        let msg = fake::message(id, 10, time, false);
+        discord.make_read_only(10);
+
+        let mut report = ChannelReport::default();
+        let err = delete_messages(
+            &discord,
+            &channel,
+            &rate_limits(),
+            &mut report,
+            &[&msg],
+            "general",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(Failure::find(&err), Failure::Forbidden);
+        assert_eq!(report.deleted, 0);
+        assert_eq!(discord.messages(10), vec![id]);
+    }
 }
@@ -1,55 +1,1312 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::Duration;
-use std::collections::HashMap;
+use reqwest::Url;
+use serenity::model::channel::Message;
+use serenity::{
+    client::validate_token,
+    model::{channel::GuildChannel, guild::GuildInfo},
+};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 use thiserror::Error;
 
+use crate::schedule::Schedule;
+
+mod file;
+mod filter;
+mod pattern;
+mod watcher;
+
+pub use file::ConfigFile;
+pub use filter::{MessageFilter, Override};
+pub use pattern::ChannelPattern;
+pub use watcher::{ConfigWatcher, EnvFile};
+
+/// The complete configuration of the bot.
+#[derive(Debug)]
+pub struct Config {
+    pub discord_token: String,
+    /// Replaces the base URL of the Discord API, e.g. for a proxy.
+    pub discord_api_url: Option<Url>,
+    pub retention: RetentionConfig,
+    pub delete_pinned: bool,
+    /// Report what would be deleted instead of deleting it.
+    pub dry_run: bool,
+    /// When the daemon runs.
+    pub schedule: Schedule,
+    /// Where to keep the progress of each channel between runs.
+    pub state_path: Option<PathBuf>,
+    /// How often channels are scanned fully despite their progress, to find
+    /// kept messages that are no longer kept, e.g. because they were unpinned.
+    pub full_scan_interval: Duration,
+    /// Where messages are archived before they're deleted, if at all.
+    pub archive_path: Option<PathBuf>,
+    /// Whether attachments are downloaded to the archive too.
+    pub archive_attachments: bool,
+    /// Larger attachments aren't downloaded, in bytes.
+    pub attachment_max_size: Option<u64>,
+    /// The lowercase file extensions of the attachments that are downloaded,
+    /// all if unset.
+    pub attachment_types: Option<Vec<String>>,
+    /// The time a sweep may spend backing off from failed requests.
+    pub retry_budget: Duration,
+    /// Where to serve Prometheus metrics and health checks, if at all.
+    pub metrics_addr: Option<SocketAddr>,
+    /// The number of failed runs in a row after which the daemon reports
+    /// itself unhealthy, 0 to never do that.
+    pub health_max_failures: u32,
+    /// How long a run may take before the daemon reports itself unhealthy.
+    pub health_stuck_after: Duration,
+}
+
+impl Config {
+    /// Loads the configuration file at the given path (if any). Environment
+    /// variables, including the ones of the `.env` file, take precedence over
+    /// the values in the file, i.e. `CHANNEL_RETENTION` replaces all retention
+    /// rules of the file.
+    pub fn load(path: Option<&Path>, env_file: &EnvFile) -> Result<Self> {
+        let config_file = match path {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let discord_token = env_file
+            .var("DISCORD_TOKEN")
+            .or_else(|| config_file.discord_token.clone())
+            .context("DISCORD_TOKEN is unset")?;
+        validate_token(&discord_token).context("Token is invalid")?;
+        let discord_api_url = match env_file
+            .var("DISCORD_API_URL")
+            .or_else(|| config_file.discord_api_url.clone())
+        {
+            Some(api_url) => Some(Url::parse(&api_url).context("Could not parse DISCORD_API_URL")?),
+            None => None,
+        };
+        let retention = match env_file.var("CHANNEL_RETENTION") {
+            Some(channel_retention) => parse_channel_retention(channel_retention)
+                .context("Could not parse CHANNEL_RETENTION")?,
+            None if config_file.has_retention() => config_file.retention_config()?,
+            None => anyhow::bail!("CHANNEL_RETENTION is unset"),
+        };
+        let delete_pinned = env_file
+            .var("DELETE_PINNED")
+            .map(|val| val == "true")
+            .or(config_file.delete_pinned)
+            .unwrap_or(false);
+        let dry_run = env_file
+            .var("DRY_RUN")
+            .map(|val| val == "true")
+            .or(config_file.dry_run)
+            .unwrap_or(false);
+        let schedule = match env_file.var("SCHEDULE").or(config_file.schedule) {
+            Some(schedule) => {
+                let timezone = env_file
+                    .var("SCHEDULE_TIMEZONE")
+                    .or(config_file.schedule_timezone);
+                Schedule::parse(&schedule, timezone.as_deref())
+                    .context("Could not parse SCHEDULE")?
+            }
+            None => Schedule::default(),
+        };
+        let state_path = env_file
+            .var("STATE_PATH")
+            .map(PathBuf::from)
+            .or(config_file.state_path);
+        let full_scan_interval = match env_file
+            .var("FULL_SCAN_INTERVAL")
+            .or(config_file.full_scan_interval)
+        {
+            Some(full_scan_interval) => {
+                parse_duration(&full_scan_interval).context("Could not parse FULL_SCAN_INTERVAL")?
+            }
+            None => default_full_scan_interval(),
+        };
+        let archive_path = env_file
+            .var("ARCHIVE_PATH")
+            .map(PathBuf::from)
+            .or(config_file.archive_path);
+        if archive_path.is_none() && retention.rules().any(|rule| rule.archive == Some(true)) {
+            anyhow::bail!("Rules with archive = true need an ARCHIVE_PATH");
+        }
+        let archive_attachments = env_file
+            .var("ARCHIVE_ATTACHMENTS")
+            .map(|val| val == "true")
+            .or(config_file.archive_attachments)
+            .unwrap_or(false);
+        if archive_attachments && archive_path.is_none() {
+            anyhow::bail!("ARCHIVE_ATTACHMENTS needs an ARCHIVE_PATH");
+        }
+        let attachment_max_size = match env_file
+            .var("ATTACHMENT_MAX_SIZE")
+            .or(config_file.attachment_max_size)
+        {
+            Some(max_size) => {
+                Some(parse_size(&max_size).context("Could not parse ATTACHMENT_MAX_SIZE")?)
+            }
+            None => None,
+        };
+        let attachment_types = env_file
+            .var("ATTACHMENT_TYPES")
+            .map(|types| types.split(',').map(str::to_string).collect())
+            .or(config_file.attachment_types)
+            .map(|types: Vec<String>| {
+                types
+                    .iter()
+                    .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                    .collect()
+            });
+        let retry_budget = match env_file.var("RETRY_BUDGET").or(config_file.retry_budget) {
+            Some(retry_budget) => {
+                parse_duration(&retry_budget).context("Could not parse RETRY_BUDGET")?
+            }
+            None => default_retry_budget(),
+        };
+        let metrics_addr = match env_file.var("METRICS_ADDR").or(config_file.metrics_addr) {
+            Some(metrics_addr) => Some(
+                metrics_addr
+                    .parse()
+                    .context("Could not parse METRICS_ADDR")?,
+            ),
+            None => None,
+        };
+        let health_max_failures = match env_file
+            .var("HEALTH_MAX_FAILURES")
+            .or(config_file.health_max_failures.map(|n| n.to_string()))
+        {
+            Some(max_failures) => max_failures
+                .parse()
+                .context("Could not parse HEALTH_MAX_FAILURES")?,
+            None => DEFAULT_HEALTH_MAX_FAILURES,
+        };
+        let health_stuck_after = match env_file
+            .var("HEALTH_STUCK_AFTER")
+            .or(config_file.health_stuck_after)
+        {
+            Some(stuck_after) => {
+                parse_duration(&stuck_after).context("Could not parse HEALTH_STUCK_AFTER")?
+            }
+            None => default_health_stuck_after(),
+        };
+
+        Ok(Config {
+            discord_token,
+            discord_api_url,
+            retention,
+            delete_pinned,
+            dry_run,
+            schedule,
+            state_path,
+            full_scan_interval,
+            archive_path,
+            archive_attachments,
+            attachment_max_size,
+            attachment_types,
+            retry_budget,
+            metrics_addr,
+            health_max_failures,
+            health_stuck_after,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            discord_token: String::default(),
+            discord_api_url: None,
+            retention: RetentionConfig::default(),
+            delete_pinned: false,
+            dry_run: false,
+            schedule: Schedule::default(),
+            state_path: None,
+            full_scan_interval: default_full_scan_interval(),
+            archive_path: None,
+            archive_attachments: false,
+            attachment_max_size: None,
+            attachment_types: None,
+            retry_budget: default_retry_budget(),
+            metrics_addr: None,
+            health_max_failures: DEFAULT_HEALTH_MAX_FAILURES,
+            health_stuck_after: default_health_stuck_after(),
+        }
+    }
+}
+
+const DEFAULT_HEALTH_MAX_FAILURES: u32 = 3;
+
+fn default_full_scan_interval() -> Duration {
+    Duration::days(1)
+}
+
+fn default_retry_budget() -> Duration {
+    Duration::minutes(5)
+}
+
+fn default_health_stuck_after() -> Duration {
+    Duration::hours(6)
+}
+
 #[derive(Error, Debug)]
 pub enum ParseChannelConfigError {
-    #[error("`{0}` is not a valid duration suffix, valid suffixes are: d, w")]
+    #[error("`{0}` is not a valid duration suffix, valid suffixes are: s, m, h, d, w")]
     InvalidDurationSuffix(char),
     #[error("duration cannot be empty")]
     NoDuration,
     #[error("invalid format")]
     InvalidFormat,
+    #[error("`{0}` is configured more than once")]
+    AmbiguousChannel(String),
+    #[error("`{0}` is not a valid pattern: {1}")]
+    InvalidPattern(String, regex::Error),
+    #[error("the patterns `{0}` and `{1}` overlap and are equally specific")]
+    OverlappingPatterns(String, String),
 }
 
-pub fn parse_channel_retention(input: String) -> Result<HashMap<String, Duration>> {
-    let mut channel_retention = HashMap::new();
-    for channel in input.split(',') {
-        let parts: Vec<&str> = channel.split(':').collect();
-        let channel_name = parts
-            .get(0)
-            .map(|str| str.to_string())
-            .ok_or(ParseChannelConfigError::InvalidFormat)?
-            .to_lowercase();
-        let mut channel_duration_str = parts
-            .get(1)
-            .map(|str| str.to_string())
-            .ok_or(ParseChannelConfigError::InvalidFormat)?;
-        let channel_duration = match channel_duration_str
-            .pop()
-            .ok_or(ParseChannelConfigError::NoDuration)?
-        {
-            'h' => Ok(Duration::hours(channel_duration_str.parse::<i64>()?)),
-            'd' => Ok(Duration::days(channel_duration_str.parse::<i64>()?)),
-            'w' => Ok(Duration::weeks(channel_duration_str.parse::<i64>()?)),
-            other => Err(ParseChannelConfigError::InvalidDurationSuffix(other)),
+/// A retention rule for one or more channels. A message is deleted if it's
+/// older than the maximum age or beyond the newest messages the rule keeps.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rule {
+    /// Messages older than this are deleted.
+    pub max_age: Option<Duration>,
+    /// Only this many of the newest messages are kept, pinned ones included.
+    pub keep: Option<u64>,
+    /// Overrides the global `DELETE_PINNED` for the channels of this rule.
+    pub delete_pinned: Option<bool>,
+    /// Whether messages are archived before they're deleted, defaults to
+    /// whether there is an `ARCHIVE_PATH`.
+    pub archive: Option<bool>,
+    /// Replace the maximum age for the messages they match, the first one that
+    /// matches wins.
+    pub overrides: Vec<Override>,
+    /// The messages this matches are never deleted.
+    pub exempt: Option<MessageFilter>,
+    /// If set, only the messages this matches are deleted.
+    pub only: Option<MessageFilter>,
+}
+
+impl Rule {
+    /// Returns the maximum age of the message: the one of the first override
+    /// that matches it, otherwise the one of the rule. `roles` are the roles
+    /// of its author.
+    pub fn max_age_of(&self, msg: &Message, roles: &[u64]) -> Option<Duration> {
+        match self.overrides.iter().find(|o| o.filter.matches(msg, roles)) {
+            Some(o) => Some(o.max_age),
+            None => self.max_age,
+        }
+    }
+
+    /// Returns whether the rule never deletes the message, because it's
+    /// exempt or not one of the messages the rule is limited to.
+    pub fn is_exempt(&self, msg: &Message, roles: &[u64]) -> bool {
+        self.exempt
+            .as_ref()
+            .is_some_and(|exempt| exempt.matches(msg, roles))
+            || self
+                .only
+                .as_ref()
+                .is_some_and(|only| !only.matches(msg, roles))
+    }
+
+    /// Returns the maximum ages of the rule and its overrides.
+    pub fn max_ages(&self) -> impl Iterator<Item = Duration> + '_ {
+        self.max_age
+            .into_iter()
+            .chain(self.overrides.iter().map(|o| o.max_age))
+    }
+
+    /// Returns whether the roles of the authors are needed to apply the rule.
+    pub fn uses_roles(&self) -> bool {
+        self.overrides
+            .iter()
+            .map(|o| &o.filter)
+            .chain(&self.exempt)
+            .chain(&self.only)
+            .any(|filter| !filter.roles.is_empty())
+    }
+}
+
+impl From<Duration> for Rule {
+    fn from(max_age: Duration) -> Self {
+        Rule {
+            max_age: Some(max_age),
+            ..Rule::default()
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.max_age, self.keep) {
+            (Some(max_age), Some(keep)) => write!(f, "{}|keep={}", format_duration(max_age), keep),
+            (Some(max_age), None) => write!(f, "{}", format_duration(max_age)),
+            (None, Some(keep)) => write!(f, "keep={}", keep),
+            (None, None) => write!(f, "keep all"),
+        }?;
+        match self.delete_pinned {
+            Some(true) => write!(f, " (delete pinned)"),
+            Some(false) => write!(f, " (keep pinned)"),
+            None => Ok(()),
+        }?;
+        match self.archive {
+            Some(true) => write!(f, " (archive)"),
+            Some(false) => write!(f, " (don't archive)"),
+            None => Ok(()),
         }?;
-        channel_retention.insert(channel_name, channel_duration);
+        for o in &self.overrides {
+            write!(f, " ({}: {})", o.filter, format_duration(o.max_age))?;
+        }
+        if let Some(only) = &self.only {
+            write!(f, " (only {})", only)?;
+        }
+        match &self.exempt {
+            Some(exempt) => write!(f, " (exempt {})", exempt),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A rule and the configuration key that selected it for a channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleMatch<'a> {
+    /// The key as in `CHANNEL_RETENTION`, prefixed with the guild if the rule
+    /// is limited to one, e.g. `my guild#log-*` or `support/*`.
+    pub key: String,
+    pub rule: &'a Rule,
+}
+
+/// Identifies a guild in the configuration, either by its id or its
+/// lowercase name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GuildKey {
+    Id(u64),
+    Name(String),
+}
+
+impl From<&str> for GuildKey {
+    fn from(input: &str) -> Self {
+        match input.parse::<u64>() {
+            Ok(id) => GuildKey::Id(id),
+            Err(_) => GuildKey::Name(input.to_lowercase()),
+        }
+    }
+}
+
+impl fmt::Display for GuildKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuildKey::Id(id) => write!(f, "{}", id),
+            GuildKey::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Identifies a channel in the configuration, either by its id, its
+/// lowercase name or `*` for all channels. A key that is a number is always an
+/// id, channels with such a name can be matched with a pattern instead.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChannelKey {
+    Id(u64),
+    Name(String),
+    Default,
+}
+
+impl From<&str> for ChannelKey {
+    fn from(input: &str) -> Self {
+        if input == "*" {
+            return ChannelKey::Default;
+        }
+        match input.parse::<u64>() {
+            Ok(id) => ChannelKey::Id(id),
+            Err(_) => ChannelKey::Name(input.to_lowercase()),
+        }
+    }
+}
+
+impl fmt::Display for ChannelKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelKey::Id(id) => write!(f, "{}", id),
+            ChannelKey::Name(name) => write!(f, "{}", name),
+            ChannelKey::Default => write!(f, "*"),
+        }
+    }
+}
+
+/// The retention of the channels in one scope, i.e. all guilds or a single
+/// guild.
+#[derive(Debug, Default)]
+pub struct ChannelRetention {
+    channels: HashMap<ChannelKey, Rule>,
+    patterns: Vec<(ChannelPattern, Rule)>,
+    /// Applies to all channels in the category, configured as `<category>/*`.
+    categories: HashMap<ChannelKey, Rule>,
+}
+
+impl ChannelRetention {
+    fn insert(&mut self, key: &str, rule: Rule) -> Result<(), ParseChannelConfigError> {
+        if let Some(category) = key.strip_suffix("/*") {
+            let category_key = match ChannelKey::from(category) {
+                ChannelKey::Name(name) if name.is_empty() => {
+                    return Err(ParseChannelConfigError::InvalidFormat)
+                }
+                ChannelKey::Default => return Err(ParseChannelConfigError::InvalidFormat),
+                category_key => category_key,
+            };
+            return match self.categories.insert(category_key, rule) {
+                Some(_) => Err(ParseChannelConfigError::AmbiguousChannel(key.to_string())),
+                None => Ok(()),
+            };
+        }
+
+        let pattern = match ChannelPattern::parse(key) {
+            Some(pattern) => {
+                pattern.map_err(|e| ParseChannelConfigError::InvalidPattern(key.to_string(), e))?
+            }
+            None => {
+                return match self.channels.insert(ChannelKey::from(key), rule) {
+                    Some(_) => Err(ParseChannelConfigError::AmbiguousChannel(key.to_string())),
+                    None => Ok(()),
+                };
+            }
+        };
+
+        for (other, _) in &self.patterns {
+            if *other == pattern {
+                return Err(ParseChannelConfigError::AmbiguousChannel(key.to_string()));
+            }
+            if other.cmp_specificity(&pattern) == Ordering::Equal && other.overlaps(&pattern) {
+                return Err(ParseChannelConfigError::OverlappingPatterns(
+                    other.to_string(),
+                    pattern.to_string(),
+                ));
+            }
+        }
+        self.patterns.push((pattern, rule));
+        Ok(())
+    }
+
+    fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.channels
+            .values()
+            .chain(self.patterns.iter().map(|(_, rule)| rule))
+            .chain(self.categories.values())
+    }
+
+    /// Returns the most specific pattern matching the given channel name and
+    /// its rule.
+    fn get_pattern(
+        &self,
+        channel_name: &str,
+    ) -> Result<Option<&(ChannelPattern, Rule)>, ParseChannelConfigError> {
+        let mut best: Option<&(ChannelPattern, Rule)> = None;
+        let mut tie: Option<&(ChannelPattern, Rule)> = None;
+        for entry in self
+            .patterns
+            .iter()
+            .filter(|(p, _)| p.is_match(channel_name))
+        {
+            match best.map(|(best_pattern, _)| entry.0.cmp_specificity(best_pattern)) {
+                None | Some(Ordering::Greater) => {
+                    best = Some(entry);
+                    tie = None;
+                }
+                Some(Ordering::Equal) => tie = Some(entry),
+                Some(Ordering::Less) => {}
+            }
+        }
+
+        match (best, tie) {
+            (Some((a, _)), Some((b, _))) => Err(ParseChannelConfigError::OverlappingPatterns(
+                a.to_string(),
+                b.to_string(),
+            )),
+            (best, _) => Ok(best),
+        }
+    }
+}
+
+/// Lists the rules in order of precedence (ids, names, patterns, categories,
+/// `*`), one per line.
+impl fmt::Display for ChannelRetention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ids: Vec<(&u64, &Rule)> = vec![];
+        let mut names: Vec<(&String, &Rule)> = vec![];
+        for (key, rule) in &self.channels {
+            match key {
+                ChannelKey::Id(id) => ids.push((id, rule)),
+                ChannelKey::Name(name) => names.push((name, rule)),
+                ChannelKey::Default => {}
+            }
+        }
+        ids.sort_by_key(|(id, _)| **id);
+        names.sort_by_key(|(name, _)| name.to_string());
+        for (id, rule) in ids {
+            writeln!(f, "  {}: {}", id, rule)?;
+        }
+        for (name, rule) in names {
+            writeln!(f, "  {}: {}", name, rule)?;
+        }
+
+        let mut patterns: Vec<&(ChannelPattern, Rule)> = self.patterns.iter().collect();
+        patterns.sort_by(|(a, _), (b, _)| b.cmp_specificity(a));
+        for (pattern, rule) in patterns {
+            writeln!(f, "  {}: {}", pattern, rule)?;
+        }
+
+        let mut categories: Vec<(String, &Rule)> = self
+            .categories
+            .iter()
+            .map(|(key, rule)| (key.to_string(), rule))
+            .collect();
+        categories.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (category, rule) in categories {
+            writeln!(f, "  {}/*: {}", category, rule)?;
+        }
+
+        if let Some(rule) = self.channels.get(&ChannelKey::Default) {
+            writeln!(f, "  *: {}", rule)?;
+        }
+        Ok(())
+    }
+}
+
+/// The retention configuration of all guilds.
+#[derive(Debug, Default)]
+pub struct RetentionConfig {
+    /// Applies to every guild that doesn't configure the channel itself.
+    global: ChannelRetention,
+    /// Applies to the given guild only.
+    guilds: HashMap<GuildKey, ChannelRetention>,
+}
+
+impl RetentionConfig {
+    /// Returns the rule for the given channel and the key that selected it.
+    ///
+    /// Channel ids are unique, so a rule for the channel id always wins.
+    /// Otherwise the rules of the guild (first by id, then by name) take
+    /// precedence over the global rules. In each the channel name takes
+    /// precedence over the most specific matching pattern, then the category
+    /// of the channel (first by id, then by name) and finally `*`.
+    ///
+    /// Fails if equally specific patterns match the channel. Those are
+    /// rejected when they're configured, so this is only a safeguard.
+    pub fn get(
+        &self,
+        guild: &GuildInfo,
+        channel: &GuildChannel,
+        category: Option<&GuildChannel>,
+    ) -> Result<Option<RuleMatch<'_>>, ParseChannelConfigError> {
+        let guild_keys = [
+            GuildKey::Id(*guild.id.as_u64()),
+            GuildKey::Name(guild.name.to_lowercase()),
+        ];
+        let scopes: Vec<(Option<&GuildKey>, &ChannelRetention)> = guild_keys
+            .iter()
+            .filter_map(|guild_key| {
+                self.guilds
+                    .get(guild_key)
+                    .map(|channel_retention| (Some(guild_key), channel_retention))
+            })
+            .chain(std::iter::once((None, &self.global)))
+            .collect();
+        let matched = |guild_key: Option<&GuildKey>, key: &dyn fmt::Display, rule| {
+            let key = match guild_key {
+                Some(guild_key) => format!("{}#{}", guild_key, key),
+                None => key.to_string(),
+            };
+            Some(RuleMatch { key, rule })
+        };
+
+        let channel_id = ChannelKey::Id(*channel.id.as_u64());
+        if let Some((guild_key, rule)) = scopes.iter().find_map(|(guild_key, channel_retention)| {
+            channel_retention
+                .channels
+                .get(&channel_id)
+                .map(|rule| (*guild_key, rule))
+        }) {
+            return Ok(matched(guild_key, &channel_id, rule));
+        }
+
+        let channel_name = ChannelKey::Name(channel.name.to_lowercase());
+        for (guild_key, channel_retention) in scopes {
+            if let Some(rule) = channel_retention.channels.get(&channel_name) {
+                return Ok(matched(guild_key, &channel_name, rule));
+            }
+            if let Some((pattern, rule)) = channel_retention.get_pattern(&channel.name)? {
+                return Ok(matched(guild_key, pattern, rule));
+            }
+            if let Some(category) = category {
+                for category_key in [
+                    ChannelKey::Id(*category.id.as_u64()),
+                    ChannelKey::Name(category.name.to_lowercase()),
+                ] {
+                    if let Some(rule) = channel_retention.categories.get(&category_key) {
+                        return Ok(matched(guild_key, &format!("{}/*", category_key), rule));
+                    }
+                }
+            }
+            if let Some(rule) = channel_retention.channels.get(&ChannelKey::Default) {
+                return Ok(matched(guild_key, &ChannelKey::Default, rule));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns all rules of all guilds.
+    pub fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.guilds
+            .values()
+            .flat_map(ChannelRetention::rules)
+            .chain(self.global.rules())
+    }
+
+    /// Sets the rule for the given channel key in the given guild, or in all
+    /// guilds if there is none.
+    pub fn insert(
+        &mut self,
+        guild: Option<&str>,
+        channel: &str,
+        rule: Rule,
+    ) -> Result<(), ParseChannelConfigError> {
+        let channel_retention = match guild {
+            Some("") => return Err(ParseChannelConfigError::InvalidFormat),
+            Some(guild) => self.guilds.entry(GuildKey::from(guild)).or_default(),
+            None => &mut self.global,
+        };
+        if channel.is_empty() {
+            return Err(ParseChannelConfigError::InvalidFormat);
+        }
+        channel_retention.insert(channel, rule)
+    }
+}
+
+impl fmt::Display for RetentionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut guilds: Vec<(String, &ChannelRetention)> = self
+            .guilds
+            .iter()
+            .map(|(key, channel_retention)| (key.to_string(), channel_retention))
+            .collect();
+        guilds.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (guild, channel_retention) in guilds {
+            writeln!(f, "Guild {}:", guild)?;
+            write!(f, "{}", channel_retention)?;
+        }
+        writeln!(f, "All guilds:")?;
+        write!(f, "{}", self.global)
+    }
+}
+
+/// Parses a comma separated list of `channel:rule` entries (see
+/// [`parse_rule`]). A channel is
+/// referenced by its id, name or a pattern (see [`ChannelPattern`]), all
+/// channels of a category by the category id or name followed by `/*`. Entries
+/// can be limited to a single guild by prefixing them with the guild id or
+/// name and a `#`, e.g. `My Guild#general:2w`.
+pub fn parse_channel_retention(input: String) -> Result<RetentionConfig> {
+    let mut retention_config = RetentionConfig::default();
+    for (key, value) in split_entries(&input)? {
+        let rule = parse_rule(value)?;
+
+        // Regular expressions can contain a `#` themselves
+        let guild_separator = if key.starts_with('/') {
+            None
+        } else {
+            key.find('#')
+        };
+        let (guild, channel) = match guild_separator {
+            Some(index) => (Some(&key[..index]), &key[index + 1..]),
+            None => (None, key),
+        };
+        retention_config
+            .insert(guild, channel, rule)
+            .map_err(|e| match e {
+                ParseChannelConfigError::AmbiguousChannel(_) => {
+                    ParseChannelConfigError::AmbiguousChannel(key.to_string())
+                }
+                other => other,
+            })?;
+    }
+    Ok(retention_config)
+}
+
+/// Splits the entries of `CHANNEL_RETENTION` into keys and rules. A
+/// regular expression can contain `,` and `:` itself, so it ends at the first
+/// `/:`.
+fn split_entries(input: &str) -> Result<Vec<(&str, &str)>, ParseChannelConfigError> {
+    let mut entries = vec![];
+    let mut rest = input;
+    loop {
+        let guild_end = rest
+            .find(['#', ':', ','])
+            .filter(|i| rest[*i..].starts_with('#'))
+            .map_or(0, |i| i + 1);
+        let key_end = if rest[guild_end..].starts_with('/') {
+            rest[guild_end + 1..]
+                .find("/:")
+                .map(|i| guild_end + 1 + i + 1)
+        } else {
+            rest.find(':').filter(|i| !rest[..*i].contains(','))
+        }
+        .ok_or(ParseChannelConfigError::InvalidFormat)?;
+
+        let (duration, next) = match rest[key_end + 1..].split_once(',') {
+            Some((duration, next)) => (duration, Some(next)),
+            None => (&rest[key_end + 1..], None),
+        };
+        entries.push((&rest[..key_end], duration));
+        match next {
+            Some(next) => rest = next,
+            None => return Ok(entries),
+        }
+    }
+}
+
+/// Parses the rule of a `CHANNEL_RETENTION` entry: a maximum age like `2w`, a
+/// number of messages to keep like `keep=500`, or both separated by `|`, e.g.
+/// `7d|keep=1000` to delete messages older than seven days or beyond the newest
+/// 1000.
+pub fn parse_rule(input: &str) -> Result<Rule> {
+    let mut rule = Rule::default();
+    for part in input.split('|') {
+        match part.strip_prefix("keep=") {
+            Some(keep) if rule.keep.is_none() => {
+                rule.keep = Some(keep.parse().context("Invalid keep")?)
+            }
+            None if rule.max_age.is_none() => rule.max_age = Some(parse_duration(part)?),
+            _ => return Err(ParseChannelConfigError::InvalidFormat.into()),
+        }
+    }
+    Ok(rule)
+}
+
+/// Parses a duration like `12h`, `2d` or `4w`.
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let mut duration_str = input.to_string();
+    match duration_str
+        .pop()
+        .ok_or(ParseChannelConfigError::NoDuration)?
+    {
+        's' => Ok(Duration::seconds(duration_str.parse::<i64>()?)),
+        'm' => Ok(Duration::minutes(duration_str.parse::<i64>()?)),
+        'h' => Ok(Duration::hours(duration_str.parse::<i64>()?)),
+        'd' => Ok(Duration::days(duration_str.parse::<i64>()?)),
+        'w' => Ok(Duration::weeks(duration_str.parse::<i64>()?)),
+        other => Err(ParseChannelConfigError::InvalidDurationSuffix(other).into()),
+    }
+}
+
+/// Parses a size in bytes like `512`, `100k`, `8m` or `1g` (powers of 1024).
+pub fn parse_size(input: &str) -> Result<u64> {
+    let (number, factor) = match input.chars().last() {
+        Some('k') => (&input[..input.len() - 1], 1 << 10),
+        Some('m') => (&input[..input.len() - 1], 1 << 20),
+        Some('g') => (&input[..input.len() - 1], 1 << 30),
+        _ => (input, 1),
+    };
+    let size = number.parse::<u64>()?;
+    size.checked_mul(factor).context("Size is too large")
+}
+
+/// Formats a duration in the largest unit `parse_duration` understands that
+/// represents it exactly, falling back to seconds.
+pub fn format_duration(duration: Duration) -> String {
+    if duration.num_seconds() != 0 && duration == Duration::weeks(duration.num_weeks()) {
+        format!("{}w", duration.num_weeks())
+    } else if duration.num_seconds() != 0 && duration == Duration::days(duration.num_days()) {
+        format!("{}d", duration.num_days())
+    } else if duration.num_seconds() != 0 && duration == Duration::hours(duration.num_hours()) {
+        format!("{}h", duration.num_hours())
+    } else if duration.num_seconds() != 0 && duration == Duration::minutes(duration.num_minutes()) {
+        format!("{}m", duration.num_minutes())
+    } else {
+        format!("{}s", duration.num_seconds())
     }
-    Ok(channel_retention)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
+
+    fn guild(id: u64, name: &str) -> GuildInfo {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "icon": null,
+            "name": name,
+            "owner": false,
+            "permissions": 0,
+        }))
+        .unwrap()
+    }
+
+    fn channel(id: u64, name: &str) -> GuildChannel {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "guild_id": "1",
+            "type": 0,
+            "name": name,
+            "position": 0,
+            "permission_overwrites": [],
+        }))
+        .unwrap()
+    }
 
     #[test]
     fn test_parse_channel_retention_simple() {
         let channel_retention = parse_channel_retention("FOO:1h,bar:2d,baz:3w".to_owned()).unwrap();
-        assert_eq!(channel_retention.get("foo").unwrap(), &Duration::hours(1));
-        assert_eq!(channel_retention.get("bar").unwrap(), &Duration::days(2));
-        assert_eq!(channel_retention.get("baz").unwrap(), &Duration::weeks(3));
+        let guild = guild(1, "guild");
+        assert_eq!(
+            channel_retention
+                .get(&guild, &channel(10, "foo"), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::hours(1))
+        );
+        assert_eq!(
+            channel_retention
+                .get(&guild, &channel(11, "bar"), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::days(2))
+        );
+        assert_eq!(
+            channel_retention
+                .get(&guild, &channel(12, "baz"), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::weeks(3))
+        );
+        assert!(channel_retention
+            .get(&guild, &channel(13, "qux"), None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_channel_retention_per_guild() {
+        let channel_retention = parse_channel_retention(
+            "general:1d,*:4w,42#general:2d,My Guild#general:3d,My Guild#*:1w".to_owned(),
+        )
+        .unwrap();
+        let general = channel(10, "general");
+        let random = channel(11, "random");
+        // Guild configured by id
+        assert_eq!(
+            channel_retention
+                .get(&guild(42, "other"), &general, None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::days(2))
+        );
+        assert_eq!(
+            channel_retention
+                .get(&guild(42, "other"), &random, None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::weeks(4))
+        );
+        // Guild configured by name
+        assert_eq!(
+            channel_retention
+                .get(&guild(1, "my guild"), &general, None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::days(3))
+        );
+        assert_eq!(
+            channel_retention
+                .get(&guild(1, "My Guild"), &random, None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::weeks(1))
+        );
+        // Unconfigured guild
+        assert_eq!(
+            channel_retention
+                .get(&guild(1, "other"), &general, None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::days(1))
+        );
+        assert_eq!(
+            channel_retention
+                .get(&guild(1, "other"), &random, None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::weeks(4))
+        );
+    }
+
+    #[test]
+    fn test_parse_channel_retention_channel_id() {
+        let channel_retention =
+            parse_channel_retention("42:1h,general:1d,My Guild#general:2d".to_owned()).unwrap();
+        // The id takes precedence over the name, even over guild rules
+        assert_eq!(
+            channel_retention
+                .get(&guild(1, "My Guild"), &channel(42, "general"), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::hours(1))
+        );
+        assert_eq!(
+            channel_retention
+                .get(&guild(1, "My Guild"), &channel(43, "general"), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::days(2))
+        );
+        assert_eq!(
+            channel_retention
+                .get(&guild(2, "other"), &channel(44, "general"), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::days(1))
+        );
+
+        // A numeric name is taken as an id, a regular expression matches it
+        let channel_retention = parse_channel_retention("2024:1h,/^2025$/:1d".to_owned()).unwrap();
+        let max_age = |channel| {
+            channel_retention
+                .get(&guild(1, "My Guild"), &channel, None)
+                .unwrap()
+                .map(|matched| matched.rule.max_age)
+        };
+        assert_eq!(max_age(channel(43, "2024")), None);
+        assert_eq!(
+            max_age(channel(2024, "log")),
+            Some(Some(Duration::hours(1)))
+        );
+        assert_eq!(max_age(channel(43, "2025")), Some(Some(Duration::days(1))));
+    }
+
+    #[test]
+    fn test_parse_channel_retention_ambiguous() {
+        for input in &[
+            "foo:1d,FOO:2d",
+            "42:1d,42:2d",
+            "*:1d,*:2w",
+            "1#foo:1d,1#foo:2d",
+        ] {
+            let result = parse_channel_retention(input.to_string());
+            if let Err(e) = result {
+                match e.downcast_ref::<ParseChannelConfigError>() {
+                    Some(ParseChannelConfigError::AmbiguousChannel(_)) => {} // Ok
+                    _ => panic!("Expected ParseChannelConfigError::AmbiguousChannel"),
+                };
+            } else {
+                panic!("Expected error for {}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_channel_retention_patterns() {
+        let channel_retention = parse_channel_retention(
+            "log-*:1d,log-audit-*:1w,log-audit:4w,tmp-??:1h,/^ticket-\\d+$/:2d,*:8w".to_owned(),
+        )
+        .unwrap();
+        let guild = guild(1, "guild");
+        let get = |name: &str| {
+            channel_retention
+                .get(&guild, &channel(10, name), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age
+                .unwrap()
+        };
+        assert_eq!(get("log-errors"), Duration::days(1));
+        assert_eq!(get("log-audit-2020"), Duration::weeks(1));
+        assert_eq!(get("log-audit"), Duration::weeks(4));
+        assert_eq!(get("tmp-42"), Duration::hours(1));
+        assert_eq!(get("tmp-421"), Duration::weeks(8));
+        assert_eq!(get("ticket-1337"), Duration::days(2));
+        assert_eq!(get("general"), Duration::weeks(8));
+    }
+
+    #[test]
+    fn test_parse_channel_retention_overlapping_patterns() {
+        for input in &["log-*:1d,*-log:2d", "/^log/:1d,/errors$/:2d"] {
+            let result = parse_channel_retention(input.to_string());
+            if let Err(e) = result {
+                match e.downcast_ref::<ParseChannelConfigError>() {
+                    Some(ParseChannelConfigError::OverlappingPatterns(_, _)) => {} // Ok
+                    _ => panic!("Expected ParseChannelConfigError::OverlappingPatterns"),
+                };
+            } else {
+                panic!("Expected error for {}", input);
+            }
+        }
+
+        // `?` is more specific than `*`, so these don't conflict
+        let channel_retention = parse_channel_retention("tmp-*:1d,tmp-??:1h".to_owned()).unwrap();
+        let get = |name: &str| {
+            channel_retention
+                .get(&guild(1, "guild"), &channel(10, name), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age
+                .unwrap()
+        };
+        assert_eq!(get("tmp-42"), Duration::hours(1));
+        assert_eq!(get("tmp-421"), Duration::days(1));
+    }
+
+    #[test]
+    fn test_parse_channel_retention_disjoint_regexes() {
+        let channel_retention =
+            parse_channel_retention("/^log-/:1d,/^tmp-/:2d".to_owned()).unwrap();
+        assert_eq!(
+            channel_retention
+                .get(&guild(1, "guild"), &channel(10, "log-errors"), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age,
+            Some(Duration::days(1))
+        );
+    }
+
+    #[test]
+    fn test_parse_channel_retention_regex_separators() {
+        let channel_retention =
+            parse_channel_retention("/^log-\\d{1,3}$/:1d,/^a:b$/:2d,*:4w".to_owned()).unwrap();
+        let get = |name: &str| {
+            channel_retention
+                .get(&guild(1, "guild"), &channel(10, name), None)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age
+                .unwrap()
+        };
+        assert_eq!(get("log-42"), Duration::days(1));
+        assert_eq!(get("log-4242"), Duration::weeks(4));
+        assert_eq!(get("a:b"), Duration::days(2));
+    }
+
+    #[test]
+    fn test_parse_channel_retention_invalid_pattern() {
+        let result = parse_channel_retention("/(/:1d".to_owned());
+        if let Err(e) = result {
+            match e.downcast_ref::<ParseChannelConfigError>() {
+                Some(ParseChannelConfigError::InvalidPattern(_, _)) => {} // Ok
+                _ => panic!("Expected ParseChannelConfigError::InvalidPattern"),
+            };
+        } else {
+            panic!("Expected error");
+        }
+    }
+
+    #[test]
+    fn test_parse_channel_retention_categories() {
+        let channel_retention =
+            parse_channel_retention("support/*:1d,42/*:1w,support-faq:4w,*:8w".to_owned()).unwrap();
+        let guild = guild(1, "guild");
+        let support = channel(40, "Support");
+        let archive = channel(42, "Archive");
+        let get = |channel: &GuildChannel, category: Option<&GuildChannel>| {
+            channel_retention
+                .get(&guild, channel, category)
+                .unwrap()
+                .unwrap()
+                .rule
+                .max_age
+                .unwrap()
+        };
+        // Inherited from the category by name and id
+        assert_eq!(
+            get(&channel(10, "tickets"), Some(&support)),
+            Duration::days(1)
+        );
+        assert_eq!(get(&channel(11, "old"), Some(&archive)), Duration::weeks(1));
+        // The channel rule overrides the category rule
+        assert_eq!(
+            get(&channel(12, "support-faq"), Some(&support)),
+            Duration::weeks(4)
+        );
+        // Channels in other or no categories use the default
+        assert_eq!(
+            get(&channel(13, "tickets"), Some(&channel(43, "Other"))),
+            Duration::weeks(8)
+        );
+        assert_eq!(get(&channel(14, "tickets"), None), Duration::weeks(8));
+    }
+
+    #[test]
+    fn test_rule_match_key() {
+        let channel_retention = parse_channel_retention(
+            "42:1h,log-*:1d,Support/*:1d,My Guild#general:1w,*:4w".to_owned(),
+        )
+        .unwrap();
+        let support = channel(40, "Support");
+        let key = |guild: &GuildInfo, channel: &GuildChannel, category: Option<&GuildChannel>| {
+            channel_retention
+                .get(guild, channel, category)
+                .unwrap()
+                .unwrap()
+                .key
+        };
+        let other = guild(1, "other");
+        assert_eq!(key(&other, &channel(42, "foo"), None), "42");
+        assert_eq!(key(&other, &channel(10, "log-foo"), None), "log-*");
+        assert_eq!(
+            key(&other, &channel(11, "faq"), Some(&support)),
+            "support/*"
+        );
+        assert_eq!(key(&other, &channel(12, "general"), None), "*");
+        assert_eq!(
+            key(&guild(2, "My Guild"), &channel(12, "general"), None),
+            "my guild#general"
+        );
+    }
+
+    #[test]
+    fn test_retention_rules() {
+        let channel_retention = parse_channel_retention(
+            "42:1h,log-*:1d,Support/*:2d,My Guild#general:1w,*:4w".to_owned(),
+        )
+        .unwrap();
+        let mut max_ages: Vec<Duration> = channel_retention
+            .rules()
+            .map(|r| r.max_age.unwrap())
+            .collect();
+        max_ages.sort();
+        assert_eq!(
+            max_ages,
+            vec![
+                Duration::hours(1),
+                Duration::days(1),
+                Duration::days(2),
+                Duration::weeks(1),
+                Duration::weeks(4)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_channel_retention_invalid_category() {
+        for input in &["/*:1d", "*/*:1d"] {
+            let result = parse_channel_retention(input.to_string());
+            if let Err(e) = result {
+                match e.downcast_ref::<ParseChannelConfigError>() {
+                    Some(ParseChannelConfigError::InvalidFormat) => {} // Ok
+                    _ => panic!("Expected ParseChannelConfigError::InvalidFormat"),
+                };
+            } else {
+                panic!("Expected error for {}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_channel_retention_empty_guild() {
+        let result = parse_channel_retention("#general:1d".to_owned());
+        if let Err(e) = result {
+            match e.downcast_ref::<ParseChannelConfigError>() {
+                Some(ParseChannelConfigError::InvalidFormat) => {} // Ok
+                _ => panic!("Expected ParseChannelConfigError::InvalidFormat"),
+            };
+        } else {
+            panic!("Expected error");
+        }
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = parse_rule("keep=500").unwrap();
+        assert_eq!((rule.max_age, rule.keep), (None, Some(500)));
+        let rule = parse_rule("7d|keep=1000").unwrap();
+        assert_eq!(
+            (rule.max_age, rule.keep),
+            (Some(Duration::days(7)), Some(1000))
+        );
+        let rule = parse_rule("keep=1000|7d").unwrap();
+        assert_eq!(
+            (rule.max_age, rule.keep),
+            (Some(Duration::days(7)), Some(1000))
+        );
+        assert_eq!(rule.to_string(), "1w|keep=1000");
+
+        assert!(parse_rule("keep=many").is_err());
+        for input in ["1d|2d", "keep=1|keep=2"] {
+            match parse_rule(input).unwrap_err().downcast_ref() {
+                Some(ParseChannelConfigError::InvalidFormat) => {} // Ok
+                _ => panic!("Expected ParseChannelConfigError::InvalidFormat"),
+            }
+        }
+
+        let channel_retention =
+            parse_channel_retention("ci-alerts:keep=500,bot-spam:7d|keep=1000".to_owned()).unwrap();
+        assert_eq!(
+            channel_retention.to_string(),
+            "All guilds:\n  bot-spam: 1w|keep=1000\n  ci-alerts: keep=500\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("100k").unwrap(), 100 * 1024);
+        assert_eq!(parse_size("8m").unwrap(), 8 * 1024 * 1024);
+        assert_eq!(parse_size("1g").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("8x").is_err());
+        assert!(parse_size("m").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::weeks(2)), "2w");
+        assert_eq!(format_duration(Duration::days(3)), "3d");
+        assert_eq!(format_duration(Duration::hours(36)), "36h");
+        assert_eq!(format_duration(Duration::minutes(90)), "90m");
+        assert_eq!(format_duration(Duration::seconds(90)), "90s");
+        assert_eq!(format_duration(Duration::zero()), "0s");
+    }
+
+    #[test]
+    fn test_display_retention_config() {
+        let channel_retention = parse_channel_retention(
+            "*:4w,log-*:1d,support/*:1w,42:2d,general:2w,1#*:1h".to_owned(),
+        )
+        .unwrap();
+        assert_eq!(
+            channel_retention.to_string(),
+            "Guild 1:\n  *: 1h\nAll guilds:\n  42: 2d\n  general: 2w\n  log-*: 1d\n  support/*: 1w\n  *: 4w\n"
+        );
     }
 
     #[test]
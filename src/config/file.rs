@@ -0,0 +1,399 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{parse_duration, parse_rule, MessageFilter, Override, RetentionConfig, Rule};
+
+/// The structure of the TOML configuration file, e.g.
+///
+/// ```toml
+/// delete_pinned = false
+/// schedule = "0 0 2 * * *"
+/// schedule_timezone = "Europe/Berlin"
+///
+/// [retention]
+/// general = "2w"
+/// "log-*" = { max_age = "1d", delete_pinned = true }
+/// ci-alerts = "keep=500"
+///
+/// [retention.general]
+/// max_age = "4w"
+/// exempt = { roles = [123456789012345678] }
+/// overrides = [{ when = { bots = true, webhooks = true }, max_age = "1d" }]
+///
+/// [guilds."My Guild".retention]
+/// "*" = "1w"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub discord_token: Option<String>,
+    /// Replaces the base URL of the Discord API.
+    pub discord_api_url: Option<String>,
+    pub delete_pinned: Option<bool>,
+    pub dry_run: Option<bool>,
+    /// An interval like `30m` or a cron expression like `0 0 2 * * *`.
+    pub schedule: Option<String>,
+    /// The timezone of cron expressions, e.g. `Europe/Berlin`.
+    pub schedule_timezone: Option<String>,
+    /// A JSON file to keep the progress of each channel in.
+    pub state_path: Option<PathBuf>,
+    /// How often channels are scanned fully despite their progress.
+    pub full_scan_interval: Option<String>,
+    /// The directory messages are archived in before they're deleted.
+    pub archive_path: Option<PathBuf>,
+    pub archive_attachments: Option<bool>,
+    /// Larger attachments aren't downloaded, e.g. `8m`.
+    pub attachment_max_size: Option<String>,
+    /// The file extensions of the attachments that are downloaded.
+    pub attachment_types: Option<Vec<String>>,
+    /// The time a sweep may spend backing off from failed requests.
+    pub retry_budget: Option<String>,
+    /// The address to serve Prometheus metrics on, e.g. `0.0.0.0:9090`.
+    pub metrics_addr: Option<String>,
+    pub health_max_failures: Option<u32>,
+    pub health_stuck_after: Option<String>,
+    /// Rules for all guilds, keyed like the entries of `CHANNEL_RETENTION`.
+    #[serde(default)]
+    pub retention: BTreeMap<String, RuleConfig>,
+    /// Rules for single guilds, keyed by guild id or name.
+    #[serde(default)]
+    pub guilds: BTreeMap<String, GuildConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuildConfig {
+    #[serde(default)]
+    pub retention: BTreeMap<String, RuleConfig>,
+}
+
+/// A rule is either written like in `CHANNEL_RETENTION`, e.g. `2w` or
+/// `7d|keep=1000`, or a table with further options.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum RuleConfig {
+    Short(String),
+    Options(Box<RuleOptions>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleOptions {
+    pub max_age: Option<String>,
+    pub keep: Option<u64>,
+    pub delete_pinned: Option<bool>,
+    pub archive: Option<bool>,
+    #[serde(default)]
+    pub overrides: Vec<OverrideOptions>,
+    /// The messages this matches are never deleted.
+    pub exempt: Option<FilterOptions>,
+    /// Only the messages this matches are deleted.
+    pub only: Option<FilterOptions>,
+}
+
+/// Replaces the maximum age for the messages `when` matches.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverrideOptions {
+    pub when: FilterOptions,
+    pub max_age: String,
+}
+
+/// Selects messages, a message matches if any of the conditions holds.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterOptions {
+    #[serde(default)]
+    pub bots: bool,
+    #[serde(default)]
+    pub webhooks: bool,
+    /// User ids.
+    #[serde(default)]
+    pub users: Vec<u64>,
+    /// Role ids.
+    #[serde(default)]
+    pub roles: Vec<u64>,
+    /// Emojis like `⭐` or names of custom emojis.
+    #[serde(default)]
+    pub reactions: Vec<String>,
+    #[serde(default)]
+    pub attachments: bool,
+    #[serde(default)]
+    pub embeds_only: bool,
+    /// A regular expression the text of the message matches.
+    pub content: Option<String>,
+}
+
+impl FilterOptions {
+    fn to_filter(&self) -> Result<MessageFilter> {
+        let filter = MessageFilter {
+            bots: self.bots,
+            webhooks: self.webhooks,
+            users: self.users.clone(),
+            roles: self.roles.clone(),
+            reactions: self.reactions.clone(),
+            attachments: self.attachments,
+            embeds_only: self.embeds_only,
+            content: match &self.content {
+                Some(content) => Some(Regex::new(content).context("Invalid content")?),
+                None => None,
+            },
+        };
+        if filter.is_empty() {
+            anyhow::bail!("The filter matches no messages");
+        }
+        Ok(filter)
+    }
+}
+
+impl RuleConfig {
+    fn to_rule(&self) -> Result<Rule> {
+        match self {
+            RuleConfig::Short(rule) => parse_rule(rule),
+            RuleConfig::Options(options) => {
+                if options.max_age.is_none()
+                    && options.keep.is_none()
+                    && options.overrides.is_empty()
+                {
+                    anyhow::bail!("A rule needs a max_age, a keep or overrides");
+                }
+                let max_age = match &options.max_age {
+                    Some(max_age) => Some(parse_duration(max_age).context("Invalid max_age")?),
+                    None => None,
+                };
+                let overrides = options
+                    .overrides
+                    .iter()
+                    .map(|o| {
+                        Ok(Override {
+                            filter: o.when.to_filter().context("Invalid override")?,
+                            max_age: parse_duration(&o.max_age)
+                                .context("Invalid max_age of override")?,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                let exempt = match &options.exempt {
+                    Some(exempt) => Some(exempt.to_filter().context("Invalid exempt")?),
+                    None => None,
+                };
+                let only = match &options.only {
+                    Some(only) => Some(only.to_filter().context("Invalid only")?),
+                    None => None,
+                };
+                Ok(Rule {
+                    max_age,
+                    keep: options.keep,
+                    delete_pinned: options.delete_pinned,
+                    archive: options.archive,
+                    overrides,
+                    exempt,
+                    only,
+                })
+            }
+        }
+    }
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<Self> {
+        let input = fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        toml::from_str(&input).with_context(|| format!("Could not parse {}", path.display()))
+    }
+
+    pub fn has_retention(&self) -> bool {
+        !self.retention.is_empty() || !self.guilds.is_empty()
+    }
+
+    /// Validates the rules of the file and converts them.
+    pub fn retention_config(&self) -> Result<RetentionConfig> {
+        let mut retention_config = RetentionConfig::default();
+        let scopes = std::iter::once((None, &self.retention)).chain(
+            self.guilds
+                .iter()
+                .map(|(guild, guild_config)| (Some(guild.as_str()), &guild_config.retention)),
+        );
+        for (guild, retention) in scopes {
+            for (channel, rule_config) in retention {
+                let table = match guild {
+                    Some(guild) => format!("guilds.{:?}.retention", guild),
+                    None => "retention".to_string(),
+                };
+                rule_config
+                    .to_rule()
+                    .and_then(|rule| Ok(retention_config.insert(guild, channel, rule)?))
+                    .with_context(|| {
+                        format!("Invalid rule for key {:?} in [{}]", channel, table)
+                    })?;
+            }
+        }
+        Ok(retention_config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ParseChannelConfigError;
+    use chrono::Duration;
+
+    #[test]
+    fn test_parse_config_file() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"
+            discord_token = "token"
+            delete_pinned = true
+
+            [retention]
+            general = "2w"
+            "log-*" = { max_age = "1d", delete_pinned = false }
+
+            [guilds."My Guild".retention]
+            "*" = "1w"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config_file.discord_token.as_deref(), Some("token"));
+        assert_eq!(config_file.delete_pinned, Some(true));
+        assert!(config_file.has_retention());
+        config_file.retention_config().unwrap();
+    }
+
+    #[test]
+    fn test_parse_config_file_rule_options() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"retention = { foo = { max_age = "3d", delete_pinned = true, archive = false } }"#,
+        )
+        .unwrap();
+        let rule = config_file.retention["foo"].to_rule().unwrap();
+        assert_eq!(rule.max_age, Some(Duration::days(3)));
+        assert_eq!(rule.keep, None);
+        assert_eq!(rule.delete_pinned, Some(true));
+        assert_eq!(rule.archive, Some(false));
+    }
+
+    #[test]
+    fn test_parse_config_file_keep() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"
+            [retention]
+            ci-alerts = "keep=500"
+            bot-spam = "7d|keep=1000"
+            log = { keep = 100, delete_pinned = true }
+            general = { delete_pinned = true }
+            "#,
+        )
+        .unwrap();
+        let rule = config_file.retention["ci-alerts"].to_rule().unwrap();
+        assert_eq!((rule.max_age, rule.keep), (None, Some(500)));
+        let rule = config_file.retention["bot-spam"].to_rule().unwrap();
+        assert_eq!(
+            (rule.max_age, rule.keep),
+            (Some(Duration::days(7)), Some(1000))
+        );
+        let rule = config_file.retention["log"].to_rule().unwrap();
+        assert_eq!((rule.max_age, rule.keep), (None, Some(100)));
+        assert!(config_file.retention["general"].to_rule().is_err());
+    }
+
+    #[test]
+    fn test_parse_config_file_author_filters() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"
+            [retention.general]
+            max_age = "4w"
+            exempt = { roles = [42] }
+
+            [[retention.general.overrides]]
+            when = { bots = true, webhooks = true }
+            max_age = "1d"
+
+            [retention.bots-only]
+            overrides = [{ when = { users = [3] }, max_age = "1h" }]
+
+            [retention.empty]
+            max_age = "1d"
+            exempt = {}
+            "#,
+        )
+        .unwrap();
+        let rule = config_file.retention["general"].to_rule().unwrap();
+        assert_eq!(rule.max_age, Some(Duration::weeks(4)));
+        assert_eq!(rule.overrides[0].max_age, Duration::days(1));
+        assert!(rule.overrides[0].filter.bots && rule.overrides[0].filter.webhooks);
+        assert_eq!(rule.exempt.as_ref().unwrap().roles, vec![42]);
+        assert_eq!(
+            rule.to_string(),
+            "4w (bots or webhooks: 1d) (exempt roles 42)"
+        );
+        let rule = config_file.retention["bots-only"].to_rule().unwrap();
+        assert_eq!(rule.max_age, None);
+        assert_eq!(
+            rule.max_ages().collect::<Vec<_>>(),
+            vec![Duration::hours(1)]
+        );
+        assert!(config_file.retention["empty"].to_rule().is_err());
+    }
+
+    #[test]
+    fn test_parse_config_file_content_filters() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"
+            [retention.announcements]
+            max_age = "4w"
+            exempt = { reactions = ["⭐", "📌"] }
+            only = { content = '^\[auto\]' }
+            overrides = [
+                { when = { attachments = true }, max_age = "8w" },
+                { when = { embeds_only = true }, max_age = "1d" },
+            ]
+
+            [retention.invalid]
+            max_age = "1d"
+            only = { content = "(" }
+            "#,
+        )
+        .unwrap();
+        let rule = config_file.retention["announcements"].to_rule().unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "4w (attachments: 8w) (embeds only: 1d) (only content /^\\[auto\\]/) \
+             (exempt reactions ⭐, 📌)"
+        );
+        let err = config_file.retention["invalid"].to_rule().unwrap_err();
+        assert!(err.to_string().contains("Invalid only"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_config_file_unknown_key() {
+        let err = toml::from_str::<ConfigFile>("\n\ndelete_pinnd = true").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_config_file_invalid_rule() {
+        let config_file: ConfigFile = toml::from_str(
+            r#"
+            [guilds."My Guild".retention]
+            general = "2x"
+            "#,
+        )
+        .unwrap();
+        let err = config_file.retention_config().unwrap_err();
+        assert!(
+            err.to_string().contains(r#"[guilds."My Guild".retention]"#),
+            "{}",
+            err
+        );
+        match err.root_cause().downcast_ref::<ParseChannelConfigError>() {
+            Some(ParseChannelConfigError::InvalidDurationSuffix('x')) => {} // Ok
+            _ => panic!("Expected ParseChannelConfigError::InvalidDurationSuffix"),
+        }
+    }
+}
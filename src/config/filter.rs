@@ -0,0 +1,240 @@
+use chrono::Duration;
+use regex::Regex;
+use serenity::model::channel::{Message, ReactionType};
+use std::fmt;
+
+/// Selects messages by their author or content. A message matches if any of
+/// the conditions holds.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    /// Messages of bot users. Webhooks are only matched by `webhooks`, even
+    /// though Discord marks them as bots too.
+    pub bots: bool,
+    /// Messages sent by webhooks.
+    pub webhooks: bool,
+    /// Messages of the users with these ids.
+    pub users: Vec<u64>,
+    /// Messages of members with any of the roles with these ids.
+    pub roles: Vec<u64>,
+    /// Messages with any of these reactions, either an emoji like `⭐` or the
+    /// name of a custom emoji.
+    pub reactions: Vec<String>,
+    /// Messages with attachments.
+    pub attachments: bool,
+    /// Messages that consist of embeds only, without text or attachments.
+    pub embeds_only: bool,
+    /// Messages whose text matches the regular expression.
+    pub content: Option<Regex>,
+}
+
+impl MessageFilter {
+    /// Returns whether the filter matches the message, `roles` are the roles
+    /// of its author.
+    pub fn matches(&self, msg: &Message, roles: &[u64]) -> bool {
+        let webhook = msg.webhook_id.is_some();
+        (self.bots && msg.author.bot && !webhook)
+            || (self.webhooks && webhook)
+            || self.users.contains(msg.author.id.as_u64())
+            || self.roles.iter().any(|role| roles.contains(role))
+            || msg.reactions.iter().any(|reaction| {
+                self.reactions
+                    .iter()
+                    .any(|emoji| is_emoji(&reaction.reaction_type, emoji))
+            })
+            || (self.attachments && !msg.attachments.is_empty())
+            || (self.embeds_only
+                && !msg.embeds.is_empty()
+                && msg.content.trim().is_empty()
+                && msg.attachments.is_empty())
+            || self
+                .content
+                .as_ref()
+                .is_some_and(|content| content.is_match(&msg.content))
+    }
+
+    /// Returns whether the filter matches no messages at all.
+    pub fn is_empty(&self) -> bool {
+        !self.bots
+            && !self.webhooks
+            && self.users.is_empty()
+            && self.roles.is_empty()
+            && self.reactions.is_empty()
+            && !self.attachments
+            && !self.embeds_only
+            && self.content.is_none()
+    }
+}
+
+/// Returns whether the reaction is the given emoji, custom emojis are given
+/// by their name with or without colons.
+fn is_emoji(reaction: &ReactionType, emoji: &str) -> bool {
+    match reaction {
+        ReactionType::Unicode(unicode) => unicode == emoji,
+        ReactionType::Custom {
+            name: Some(name), ..
+        } => name == emoji.trim_matches(':'),
+        _ => false,
+    }
+}
+
+/// Regular expressions can't be compared, so filters are compared by their
+/// conditions as displayed.
+impl PartialEq for MessageFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl fmt::Display for MessageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |ids: &[u64]| {
+            ids.iter()
+                .map(u64::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut conditions = vec![];
+        if self.bots {
+            conditions.push("bots".to_string());
+        }
+        if self.webhooks {
+            conditions.push("webhooks".to_string());
+        }
+        if !self.users.is_empty() {
+            conditions.push(format!("users {}", join(&self.users)));
+        }
+        if !self.roles.is_empty() {
+            conditions.push(format!("roles {}", join(&self.roles)));
+        }
+        if !self.reactions.is_empty() {
+            conditions.push(format!("reactions {}", self.reactions.join(", ")));
+        }
+        if self.attachments {
+            conditions.push("attachments".to_string());
+        }
+        if self.embeds_only {
+            conditions.push("embeds only".to_string());
+        }
+        if let Some(content) = &self.content {
+            conditions.push(format!("content /{}/", content));
+        }
+        write!(f, "{}", conditions.join(" or "))
+    }
+}
+
+/// Replaces the maximum age of a rule for the messages the filter matches,
+/// e.g. to delete the messages of bots sooner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub filter: MessageFilter,
+    pub max_age: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discord::fake::{attachment, author, message_with};
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn test_message_filter() {
+        let now = Utc::now();
+        let human = message_with(1, 10, now, json!({ "author": author(2, false) }));
+        let bot = message_with(2, 10, now, json!({ "author": author(3, true) }));
+        let webhook = message_with(
+            3,
+            10,
+            now,
+            json!({ "author": author(4, true), "webhook_id": "4" }),
+        );
+
+        let bots = MessageFilter {
+            bots: true,
+            ..MessageFilter::default()
+        };
+        assert!(!bots.matches(&human, &[]));
+        assert!(bots.matches(&bot, &[]));
+        assert!(!bots.matches(&webhook, &[]));
+
+        let webhooks = MessageFilter {
+            webhooks: true,
+            ..MessageFilter::default()
+        };
+        assert!(!webhooks.matches(&bot, &[]));
+        assert!(webhooks.matches(&webhook, &[]));
+
+        let users_or_roles = MessageFilter {
+            users: vec![3],
+            roles: vec![42],
+            ..MessageFilter::default()
+        };
+        assert!(!users_or_roles.matches(&human, &[41]));
+        assert!(users_or_roles.matches(&human, &[41, 42]));
+        assert!(users_or_roles.matches(&bot, &[]));
+        assert_eq!(users_or_roles.to_string(), "users 3 or roles 42");
+
+        assert!(MessageFilter::default().is_empty());
+        assert!(!MessageFilter::default().matches(&human, &[42]));
+    }
+
+    #[test]
+    fn test_message_filter_content() {
+        let now = Utc::now();
+        let plain = message_with(1, 10, now, json!({ "content": "build passed" }));
+        let starred = message_with(
+            2,
+            10,
+            now,
+            json!({ "reactions": [
+                { "count": 1, "me": false, "emoji": { "id": null, "name": "⭐" } },
+                { "count": 2, "me": false, "emoji": { "id": "5", "name": "party" } },
+            ] }),
+        );
+        let with_attachment = message_with(
+            3,
+            10,
+            now,
+            json!({ "attachments": [attachment(4, 10, "foo.txt", 3)] }),
+        );
+        let embeds = json!([{ "type": "rich", "title": "Build failed" }]);
+        let embeds_only = message_with(4, 10, now, json!({ "content": "", "embeds": embeds }));
+        let embeds_and_text = message_with(5, 10, now, json!({ "embeds": embeds }));
+
+        let reactions = MessageFilter {
+            reactions: vec!["📌".to_string(), "⭐".to_string()],
+            ..MessageFilter::default()
+        };
+        assert!(reactions.matches(&starred, &[]));
+        assert!(!reactions.matches(&plain, &[]));
+        let custom = MessageFilter {
+            reactions: vec![":party:".to_string()],
+            ..MessageFilter::default()
+        };
+        assert!(custom.matches(&starred, &[]));
+
+        let attachments = MessageFilter {
+            attachments: true,
+            ..MessageFilter::default()
+        };
+        assert!(attachments.matches(&with_attachment, &[]));
+        assert!(!attachments.matches(&plain, &[]));
+
+        let only_embeds = MessageFilter {
+            embeds_only: true,
+            ..MessageFilter::default()
+        };
+        assert!(only_embeds.matches(&embeds_only, &[]));
+        assert!(!only_embeds.matches(&embeds_and_text, &[]));
+        assert!(!only_embeds.matches(&plain, &[]));
+
+        let content = MessageFilter {
+            content: Some(Regex::new("^build (passed|succeeded)").unwrap()),
+            ..MessageFilter::default()
+        };
+        assert!(content.matches(&plain, &[]));
+        assert!(!content.matches(&embeds_and_text, &[]));
+        assert_eq!(content.to_string(), "content /^build (passed|succeeded)/");
+        assert_ne!(content, MessageFilter::default());
+    }
+}
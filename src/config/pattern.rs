@@ -0,0 +1,277 @@
+use regex::Regex;
+use regex_automata::{
+    dfa::{dense, Automaton, StartKind},
+    util::{primitives::StateID, start},
+    MatchKind,
+};
+use std::{cmp::Ordering, collections::HashSet};
+
+/// The number of state pairs `regexes_intersect` explores before it gives up
+/// and assumes the regular expressions overlap.
+const MAX_INTERSECTION_STATES: usize = 100_000;
+
+/// A pattern matching channel names, either a glob like `log-*` or `tmp-??`
+/// or a regular expression enclosed in slashes like `/^ticket-\d+$/`.
+#[derive(Debug, Clone)]
+pub enum ChannelPattern {
+    Glob { source: String, regex: Regex },
+    Regex(Regex),
+}
+
+impl ChannelPattern {
+    /// Parses the given input as pattern. Returns `None` if it's not a
+    /// pattern but a plain channel name or `*`, which is handled separately.
+    pub fn parse(input: &str) -> Option<Result<Self, regex::Error>> {
+        if input == "*" {
+            None
+        } else if input.len() >= 2 && input.starts_with('/') && input.ends_with('/') {
+            Some(Regex::new(&input[1..input.len() - 1]).map(ChannelPattern::Regex))
+        } else if input.contains(['*', '?']) {
+            let source = input.to_lowercase();
+            let mut regex = String::from("^");
+            for c in source.chars() {
+                match c {
+                    '*' => regex.push_str(".*"),
+                    '?' => regex.push('.'),
+                    other => regex.push_str(&regex::escape(&other.to_string())),
+                }
+            }
+            regex.push('$');
+            Some(Regex::new(&regex).map(|regex| ChannelPattern::Glob { source, regex }))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_match(&self, channel_name: &str) -> bool {
+        match self {
+            ChannelPattern::Glob { regex, .. } => regex.is_match(&channel_name.to_lowercase()),
+            ChannelPattern::Regex(regex) => regex.is_match(channel_name),
+        }
+    }
+
+    /// Compares how specific two patterns are. Globs are more specific the
+    /// more literal characters they contain, then the more `?` they contain,
+    /// as those match a single character only. Regular expressions are less
+    /// specific than any glob and equally specific among each other.
+    pub fn cmp_specificity(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (ChannelPattern::Glob { source: a, .. }, ChannelPattern::Glob { source: b, .. }) => {
+                specificity(a).cmp(&specificity(b))
+            }
+            (ChannelPattern::Glob { .. }, ChannelPattern::Regex(_)) => Ordering::Greater,
+            (ChannelPattern::Regex(_), ChannelPattern::Glob { .. }) => Ordering::Less,
+            (ChannelPattern::Regex(_), ChannelPattern::Regex(_)) => Ordering::Equal,
+        }
+    }
+
+    /// Returns whether both patterns match at least one common channel name.
+    /// Only globs and regular expressions among each other are compared, as
+    /// they're never equally specific otherwise.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ChannelPattern::Glob { source: a, .. }, ChannelPattern::Glob { source: b, .. }) => {
+                globs_intersect(a, b)
+            }
+            (ChannelPattern::Regex(a), ChannelPattern::Regex(b)) => {
+                regexes_intersect(a.as_str(), b.as_str())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelPattern::Glob { source, .. } => write!(f, "{}", source),
+            ChannelPattern::Regex(regex) => write!(f, "/{}/", regex.as_str()),
+        }
+    }
+}
+
+impl PartialEq for ChannelPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+/// Counts the literal characters and the `?` of a glob.
+fn specificity(glob: &str) -> (usize, usize) {
+    let literals = glob.chars().filter(|c| *c != '*' && *c != '?').count();
+    let single_wildcards = glob.chars().filter(|c| *c == '?').count();
+    (literals, single_wildcards)
+}
+
+/// Walks both globs in lockstep and checks whether they can reach their ends
+/// on the same input.
+fn globs_intersect(a: &str, b: &str) -> bool {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut seen = HashSet::new();
+    let mut queue = vec![(0, 0)];
+    while let Some((i, j)) = queue.pop() {
+        if !seen.insert((i, j)) {
+            continue;
+        }
+        if i == a.len() && j == b.len() {
+            return true;
+        }
+
+        // A `*` can match nothing
+        if a.get(i) == Some(&'*') {
+            queue.push((i + 1, j));
+        }
+        if b.get(j) == Some(&'*') {
+            queue.push((i, j + 1));
+        }
+
+        // Or both consume the same character
+        if let (Some(&x), Some(&y)) = (a.get(i), b.get(j)) {
+            let literals_differ = x != '*' && x != '?' && y != '*' && y != '?' && x != y;
+            if !literals_differ {
+                let next_i = if x == '*' { i } else { i + 1 };
+                let next_j = if y == '*' { j } else { j + 1 };
+                queue.push((next_i, next_j));
+            }
+        }
+    }
+    false
+}
+
+/// Runs the DFAs of both regular expressions in lockstep over all inputs and
+/// checks whether both find a match in the same one. Expressions that are too
+/// complex to compare this way are considered overlapping.
+fn regexes_intersect(a: &str, b: &str) -> bool {
+    let (a, b) = match (build_dfa(a), build_dfa(b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return true,
+    };
+    let start_config = start::Config::new();
+    let (start_a, start_b) = match (a.start_state(&start_config), b.start_state(&start_config)) {
+        (Ok(start_a), Ok(start_b)) => (start_a, start_b),
+        _ => return true,
+    };
+
+    // A state is the state of each DFA and whether it already found a match
+    let mut seen: HashSet<(StateID, bool, StateID, bool)> = HashSet::new();
+    let mut queue = vec![(start_a, false, start_b, false)];
+    while let Some(state) = queue.pop() {
+        if !seen.insert(state) {
+            continue;
+        }
+        if seen.len() > MAX_INTERSECTION_STATES {
+            return true;
+        }
+        let (state_a, matched_a, state_b, matched_b) = state;
+        if (matched_a || a.is_match_state(a.next_eoi_state(state_a)))
+            && (matched_b || b.is_match_state(b.next_eoi_state(state_b)))
+        {
+            return true;
+        }
+
+        for byte in 0..=255 {
+            let next_a = a.next_state(state_a, byte);
+            let next_b = b.next_state(state_b, byte);
+            let next_matched_a = matched_a || a.is_match_state(next_a);
+            let next_matched_b = matched_b || b.is_match_state(next_b);
+            if (a.is_dead_state(next_a) && !next_matched_a)
+                || (b.is_dead_state(next_b) && !next_matched_b)
+            {
+                continue;
+            }
+            queue.push((next_a, next_matched_a, next_b, next_matched_b));
+        }
+    }
+    false
+}
+
+fn build_dfa(regex: &str) -> Option<dense::DFA<Vec<u32>>> {
+    dense::Builder::new()
+        .configure(
+            dense::Config::new()
+                .match_kind(MatchKind::All)
+                .start_kind(StartKind::Unanchored),
+        )
+        .build(regex)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(input: &str) -> ChannelPattern {
+        ChannelPattern::parse(input).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(ChannelPattern::parse("general").is_none());
+        assert!(ChannelPattern::parse("*").is_none());
+        assert!(matches!(pattern("log-*"), ChannelPattern::Glob { .. }));
+        assert!(matches!(
+            pattern("/^ticket-\\d+$/"),
+            ChannelPattern::Regex(_)
+        ));
+        assert!(ChannelPattern::parse("/(/").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_is_match() {
+        assert!(pattern("log-*").is_match("log-errors"));
+        assert!(!pattern("log-*").is_match("catalog-errors"));
+        assert!(pattern("tmp-??").is_match("tmp-42"));
+        assert!(!pattern("tmp-??").is_match("tmp-421"));
+        assert!(pattern("a.b*").is_match("a.bc"));
+        assert!(!pattern("a.b*").is_match("axbc"));
+        assert!(pattern("/^ticket-\\d+$/").is_match("ticket-123"));
+        assert!(!pattern("/^ticket-\\d+$/").is_match("ticket-abc"));
+    }
+
+    #[test]
+    fn test_cmp_specificity() {
+        assert_eq!(
+            pattern("log-err-*").cmp_specificity(&pattern("log-*")),
+            Ordering::Greater
+        );
+        assert_eq!(
+            pattern("tmp-??").cmp_specificity(&pattern("tmp-*")),
+            Ordering::Greater
+        );
+        assert_eq!(
+            pattern("log-*").cmp_specificity(&pattern("*-log")),
+            Ordering::Equal
+        );
+        assert_eq!(
+            pattern("*-?").cmp_specificity(&pattern("/^a$/")),
+            Ordering::Greater
+        );
+        assert_eq!(
+            pattern("/a/").cmp_specificity(&pattern("/b/")),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(pattern("log-*").overlaps(&pattern("*-log")));
+        assert!(pattern("a?c").overlaps(&pattern("ab?")));
+        assert!(pattern("f*").overlaps(&pattern("?o")));
+        assert!(!pattern("log-*").overlaps(&pattern("tmp-*")));
+        assert!(!pattern("tmp-??").overlaps(&pattern("tmp-?")));
+        assert!(!pattern("a*b").overlaps(&pattern("c*")));
+        assert!(!pattern("log-*").overlaps(&pattern("/^log/")));
+    }
+
+    #[test]
+    fn test_overlaps_regexes() {
+        assert!(pattern("/^log/").overlaps(&pattern("/errors$/")));
+        assert!(pattern("/a/").overlaps(&pattern("/a/")));
+        assert!(pattern("/^ticket-\\d+$/").overlaps(&pattern("/-1$/")));
+        assert!(!pattern("/^log-/").overlaps(&pattern("/^tmp-/")));
+        assert!(!pattern("/^\\d+$/").overlaps(&pattern("/[a-z]/")));
+        assert!(!pattern("/^a$/").overlaps(&pattern("/^ab$/")));
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::Result;
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use super::Config;
+
+/// The variables of an `.env` file. Like with `dotenv`, they don't override
+/// the ones set in the environment. Unlike `dotenv` they're kept apart instead
+/// of being applied to the environment, which isn't safe to change while
+/// other threads might read it.
+#[derive(Debug, Default)]
+pub struct EnvFile {
+    path: Option<PathBuf>,
+    vars: HashMap<String, String>,
+}
+
+impl EnvFile {
+    /// Looks for an `.env` file in the current directory and its parents and
+    /// reads it. Without a readable file there are no variables.
+    pub fn load() -> Self {
+        let path = match find_env_file() {
+            Some(path) => path,
+            None => return EnvFile::default(),
+        };
+        let mut env_file = EnvFile {
+            path: Some(path),
+            vars: HashMap::new(),
+        };
+        if let Err(e) = env_file.reload() {
+            warn!("Could not read .env file: {:?}", e);
+        }
+        env_file
+    }
+
+    /// Returns the variable from the environment or, if it's unset there,
+    /// from the file.
+    pub fn var(&self, key: &str) -> Option<String> {
+        env::var(key).ok().or_else(|| self.vars.get(key).cloned())
+    }
+
+    /// Reads the current content of the file, variables that are no longer
+    /// in it are dropped.
+    fn reload(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut vars = HashMap::new();
+        for item in dotenvy::from_path_iter(path)? {
+            let (key, value) = item?;
+            vars.insert(key, value);
+        }
+        self.vars = vars;
+        Ok(())
+    }
+}
+
+fn find_env_file() -> Option<PathBuf> {
+    let current_dir = env::current_dir().ok()?;
+    current_dir
+        .ancestors()
+        .map(|dir| dir.join(".env"))
+        .find(|path| path.is_file())
+}
+
+/// Reloads the configuration when the configuration file or `.env` file
+/// changed or the process received a SIGHUP.
+pub struct ConfigWatcher {
+    config_path: Option<PathBuf>,
+    env_file: EnvFile,
+    modified: HashMap<PathBuf, Option<SystemTime>>,
+    hangup: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    /// Creates a new watcher, this needs to be called within the runtime to
+    /// listen for SIGHUP.
+    pub fn new(config_path: Option<PathBuf>, env_file: EnvFile) -> Result<Self> {
+        let hangup = Arc::new(AtomicBool::new(false));
+        listen_for_hangup(hangup.clone())?;
+
+        let mut watcher = ConfigWatcher {
+            config_path,
+            env_file,
+            modified: HashMap::new(),
+            hangup,
+        };
+        watcher.has_changed(); // remember the current modification times
+        Ok(watcher)
+    }
+
+    /// Loads the configuration from the current sources.
+    pub fn load(&self) -> Result<Config> {
+        Config::load(self.config_path.as_deref(), &self.env_file)
+    }
+
+    /// Returns the new configuration if there was a SIGHUP or one of the
+    /// files changed since the last call. If the new configuration is invalid
+    /// the error is logged and `None` is returned, so the caller can keep
+    /// using the previous one.
+    pub fn reload(&mut self) -> Option<Config> {
+        let hangup = self.hangup.swap(false, Ordering::SeqCst);
+        if hangup {
+            info!("Received SIGHUP, reloading configuration");
+        } else if self.has_changed() {
+            info!("Configuration changed, reloading");
+        } else {
+            return None;
+        }
+
+        if let Err(e) = self.env_file.reload() {
+            error!("Could not reload .env file: {:?}", e);
+            return None;
+        }
+        match self.load() {
+            Ok(config) => Some(config),
+            Err(e) => {
+                error!(
+                    "Could not reload configuration, keeping the previous one: {:?}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Checks the modification times of all files and returns whether any
+    /// of them changed since the last call.
+    fn has_changed(&mut self) -> bool {
+        let paths: Vec<PathBuf> = self
+            .config_path
+            .iter()
+            .chain(self.env_file.path.iter())
+            .cloned()
+            .collect();
+
+        let mut changed = false;
+        for path in paths {
+            let modified = modified(&path);
+            if self.modified.insert(path, modified) != Some(modified) {
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => Some(modified),
+        Err(e) => {
+            warn!("Could not check {} for changes: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(unix)]
+fn listen_for_hangup(hangup: Arc<AtomicBool>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            hangup.store(true, Ordering::SeqCst);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_for_hangup(_hangup: Arc<AtomicBool>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_file() {
+        let path =
+            env::temp_dir().join(format!("discord-retention-bot-{}.env", std::process::id()));
+        fs::write(&path, "DISCORD_RETENTION_BOT_TEST=foo\nPATH=foo\n").unwrap();
+        let mut env_file = EnvFile {
+            path: Some(path.clone()),
+            vars: HashMap::new(),
+        };
+        env_file.reload().unwrap();
+        assert_eq!(
+            env_file.var("DISCORD_RETENTION_BOT_TEST").as_deref(),
+            Some("foo")
+        );
+        // The environment takes precedence
+        assert_ne!(env_file.var("PATH").as_deref(), Some("foo"));
+
+        fs::write(&path, "PATH=foo\n").unwrap();
+        env_file.reload().unwrap();
+        assert_eq!(env_file.var("DISCORD_RETENTION_BOT_TEST"), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
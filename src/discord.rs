@@ -0,0 +1,244 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use serenity::{
+    http::{request::RequestBuilder, routing::RouteInfo, HttpError},
+    model::{
+        channel::{GuildChannel, Message},
+        guild::{GuildInfo, PartialMember},
+    },
+};
+use std::time::Duration;
+
+use crate::ratelimit::RateLimitInfo;
+
+#[cfg(test)]
+pub mod fake;
+#[cfg(test)]
+pub mod stand_in;
+
+/// The base URL serenity builds the requests for.
+const SERENITY_API_URL: &str = "https://discord.com/api/v6";
+
+/// How long a request to the API may take before it's retried.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long connecting to the API may take before the request is retried.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long downloading an attachment may take, they can be large.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The result of a request and the rate limit Discord reported with it.
+pub type Response<T> = (serenity::Result<T>, RateLimitInfo);
+
+/// The parts of the Discord API the bot uses. [`Client`] talks to Discord,
+/// tests use [`fake::FakeDiscord`].
+#[async_trait]
+pub trait DiscordApi: Sync {
+    /// Lists up to `limit` guilds of the bot with an id greater than `after`.
+    async fn get_guilds(&self, after: u64, limit: u64) -> Response<Vec<GuildInfo>>;
+
+    async fn get_channels(&self, guild_id: u64) -> Response<Vec<GuildChannel>>;
+
+    /// Gets the member of the guild with the given user id, e.g. for its roles.
+    async fn get_member(&self, guild_id: u64, user_id: u64) -> Response<PartialMember>;
+
+    /// Lists up to `limit` messages of the channel older than the message id
+    /// `before` (or the newest ones without it), newest first.
+    async fn get_messages(
+        &self,
+        channel_id: u64,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Response<Vec<Message>>;
+
+    async fn delete_message(&self, channel_id: u64, message_id: u64) -> Response<()>;
+
+    /// Deletes the messages with the Bulk Delete Messages endpoint, `map` is
+    /// its body.
+    async fn delete_messages(&self, channel_id: u64, map: &Value) -> Response<()>;
+
+    /// Downloads a file from Discord's CDN, e.g. an attachment.
+    async fn download(&self, url: &str) -> Result<Vec<u8>>;
+}
+
+/// Sends the requests of the bot to the Discord API.
+///
+/// Serenity builds the requests and parses the responses, but unlike its own
+/// client this one doesn't wait for rate limits. It returns the rate limit
+/// headers instead, so [`RateLimits`](crate::ratelimit::RateLimits) can wait
+/// and account the time to the guild that made the request.
+pub struct Client {
+    http: reqwest::Client,
+    token: String,
+    /// Replaces Discord's base URL, e.g. to talk to a stand-in.
+    api_url: Option<Url>,
+}
+
+impl Client {
+    pub fn new(token: &str, api_url: Option<&Url>) -> Self {
+        let token = if token.trim().starts_with("Bot ") {
+            token.to_string()
+        } else {
+            format!("Bot {}", token)
+        };
+        let http = reqwest::Client::builder()
+            .use_rustls_tls()
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .expect("Cannot build HTTP client");
+        Client {
+            http,
+            token,
+            api_url: api_url.cloned(),
+        }
+    }
+
+    /// Performs the request and parses the response body.
+    async fn fire<T: DeserializeOwned>(
+        &self,
+        route: RouteInfo<'_>,
+        body: Option<&[u8]>,
+    ) -> Response<T> {
+        let (res, rate_limit) = self.request(route, body).await;
+        let res = match res {
+            Ok(response) => response.json().await.map_err(From::from),
+            Err(e) => Err(e),
+        };
+        (res, rate_limit)
+    }
+
+    /// Performs the request and ignores the response body.
+    async fn wind(&self, route: RouteInfo<'_>, body: Option<&[u8]>) -> Response<()> {
+        let (res, rate_limit) = self.request(route, body).await;
+        (res.map(|_| ()), rate_limit)
+    }
+
+    /// Performs the request, unsuccessful responses are turned into errors.
+    async fn request(
+        &self,
+        route: RouteInfo<'_>,
+        body: Option<&[u8]>,
+    ) -> Response<reqwest::Response> {
+        let mut builder = RequestBuilder::new(route);
+        builder.body(body);
+        let request = builder.build();
+        let built = request
+            .build(&self.http, &self.token)
+            .map_err(serenity::Error::from)
+            .and_then(|builder| builder.build().map_err(serenity::Error::from));
+        let sent = match built {
+            Ok(mut request) => {
+                if let Some(api_url) = &self.api_url {
+                    *request.url_mut() = with_base_url(request.url(), api_url);
+                }
+                self.http.execute(request).await
+            }
+            Err(e) => return (Err(e), RateLimitInfo::default()),
+        };
+        let response = match sent {
+            Ok(response) => response,
+            Err(e) => return (Err(e.into()), RateLimitInfo::default()),
+        };
+
+        let rate_limit = RateLimitInfo::from_response(response.status(), response.headers());
+        if response.status().is_success() {
+            (Ok(response), rate_limit)
+        } else {
+            let err = HttpError::from_response(response).await;
+            (Err(serenity::Error::Http(Box::new(err))), rate_limit)
+        }
+    }
+}
+
+/// Moves a URL serenity built to the given base URL.
+fn with_base_url(url: &Url, api_url: &Url) -> Url {
+    let path = match url.as_str().strip_prefix(SERENITY_API_URL) {
+        Some(path) => path,
+        None => return url.clone(),
+    };
+    let base = api_url.as_str().trim_end_matches('/');
+    Url::parse(&format!("{}{}", base, path)).unwrap_or_else(|_| url.clone())
+}
+
+#[async_trait]
+impl DiscordApi for Client {
+    async fn get_guilds(&self, after: u64, limit: u64) -> Response<Vec<GuildInfo>> {
+        let route = RouteInfo::GetGuilds {
+            after: Some(after),
+            before: None,
+            limit,
+        };
+        self.fire(route, None).await
+    }
+
+    async fn get_channels(&self, guild_id: u64) -> Response<Vec<GuildChannel>> {
+        self.fire(RouteInfo::GetChannels { guild_id }, None).await
+    }
+
+    async fn get_member(&self, guild_id: u64, user_id: u64) -> Response<PartialMember> {
+        self.fire(RouteInfo::GetMember { guild_id, user_id }, None)
+            .await
+    }
+
+    async fn get_messages(
+        &self,
+        channel_id: u64,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Response<Vec<Message>> {
+        let query = match before {
+            Some(before) => format!("?limit={}&before={}", limit, before),
+            None => format!("?limit={}", limit),
+        };
+        let route = RouteInfo::GetMessages { channel_id, query };
+        self.fire(route, None).await
+    }
+
+    async fn delete_message(&self, channel_id: u64, message_id: u64) -> Response<()> {
+        let route = RouteInfo::DeleteMessage {
+            channel_id,
+            message_id,
+        };
+        self.wind(route, None).await
+    }
+
+    async fn delete_messages(&self, channel_id: u64, map: &Value) -> Response<()> {
+        let body = map.to_string();
+        let route = RouteInfo::DeleteMessages { channel_id };
+        self.wind(route, Some(body.as_bytes())).await
+    }
+
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self
+            .http
+            .get(url)
+            .timeout(DOWNLOAD_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_base_url() {
+        let url = Url::parse("https://discord.com/api/v6/channels/1/messages?limit=100").unwrap();
+        let api_url = Url::parse("http://127.0.0.1:8080/api/").unwrap();
+        assert_eq!(
+            with_base_url(&url, &api_url).as_str(),
+            "http://127.0.0.1:8080/api/channels/1/messages?limit=100"
+        );
+
+        let other = Url::parse("https://cdn.discordapp.com/attachments/1/2/foo.txt").unwrap();
+        assert_eq!(with_base_url(&other, &api_url), other);
+    }
+}
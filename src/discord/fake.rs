@@ -0,0 +1,362 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use serenity::{
+    http::{error::ErrorResponse, HttpError, StatusCode},
+    model::{
+        channel::{GuildChannel, Message},
+        guild::{GuildInfo, PartialMember},
+        id::MessageId,
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use super::{DiscordApi, Response};
+use crate::{
+    bot::{snowflake_at, MISSING_ACCESS, MISSING_PERMISSIONS, UNKNOWN_MEMBER, UNKNOWN_MESSAGE},
+    ratelimit::RateLimitInfo,
+};
+
+/// Discord's error code for an invalid request body.
+const INVALID_FORM_BODY: isize = 50035;
+
+/// How long a rate limited request has to wait.
+pub const RETRY_AFTER: Duration = Duration::from_millis(10);
+
+/// An in-memory Discord for offline tests. It pages like Discord, refuses
+/// channels the bot lacks permissions for, rate limits on request and records
+/// every request it gets.
+#[derive(Debug, Default)]
+pub struct FakeDiscord {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    guilds: BTreeMap<u64, GuildInfo>,
+    channels: BTreeMap<u64, GuildChannel>,
+    /// The roles of the members of each guild by user id.
+    members: HashMap<(u64, u64), Vec<u64>>,
+    /// The messages of each channel by id.
+    messages: HashMap<u64, BTreeMap<u64, Message>>,
+    /// Channels the bot can't read.
+    hidden: HashSet<u64>,
+    /// Channels the bot can read, but not delete messages in.
+    read_only: HashSet<u64>,
+    /// The number of upcoming requests that are rate limited.
+    rate_limited: u32,
+    /// Whether bulk deletes fail, as if the messages were too old.
+    reject_bulk_deletes: bool,
+    /// Files on the CDN by URL.
+    files: HashMap<String, Vec<u8>>,
+    requests: Vec<String>,
+}
+
+impl FakeDiscord {
+    pub fn add_guild(&self, id: u64, name: &str) -> GuildInfo {
+        let guild: GuildInfo = serde_json::from_value(json!({
+            "id": id.to_string(),
+            "icon": null,
+            "name": name,
+            "owner": false,
+            "permissions": 0,
+        }))
+        .unwrap();
+        self.state().guilds.insert(id, guild.clone());
+        guild
+    }
+
+    pub fn add_channel(&self, guild_id: u64, id: u64, name: &str) -> GuildChannel {
+        let channel: GuildChannel = serde_json::from_value(json!({
+            "id": id.to_string(),
+            "guild_id": guild_id.to_string(),
+            "type": 0,
+            "name": name,
+            "position": 0,
+            "permission_overwrites": [],
+        }))
+        .unwrap();
+        self.state().channels.insert(id, channel.clone());
+        channel
+    }
+
+    pub fn add_member(&self, guild_id: u64, user_id: u64, roles: &[u64]) {
+        self.state()
+            .members
+            .insert((guild_id, user_id), roles.to_vec());
+    }
+
+    /// Adds a message sent at the given time and returns its id. Messages
+    /// sent at the same time get consecutive ids.
+    pub fn add_message(&self, channel_id: u64, time: DateTime<Utc>, pinned: bool) -> u64 {
+        self.add_message_with(channel_id, time, json!({ "pinned": pinned }))
+    }
+
+    /// Adds a message like [`add_message`](Self::add_message), with the given
+    /// fields replaced, e.g. its `author`.
+    pub fn add_message_with(&self, channel_id: u64, time: DateTime<Utc>, fields: Value) -> u64 {
+        let mut state = self.state();
+        let messages = state.messages.entry(channel_id).or_default();
+        let mut id = snowflake_at(time);
+        while messages.contains_key(&id) {
+            id += 1;
+        }
+        messages.insert(id, message_with(id, channel_id, time, fields));
+        id
+    }
+
+    /// Returns the ids of the messages left in the channel, oldest first.
+    pub fn messages(&self, channel_id: u64) -> Vec<u64> {
+        let state = self.state();
+        state
+            .messages
+            .get(&channel_id)
+            .map(|messages| messages.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Hides the channel from the bot, as if it lacked Read Message History.
+    pub fn hide_channel(&self, channel_id: u64) {
+        self.state().hidden.insert(channel_id);
+    }
+
+    /// Lets the bot read but not delete messages, as if it lacked Manage
+    /// Messages.
+    pub fn make_read_only(&self, channel_id: u64) {
+        self.state().read_only.insert(channel_id);
+    }
+
+    /// Rate limits the next requests, they need to wait for [`RETRY_AFTER`].
+    pub fn rate_limit(&self, requests: u32) {
+        self.state().rate_limited = requests;
+    }
+
+    pub fn reject_bulk_deletes(&self) {
+        self.state().reject_bulk_deletes = true;
+    }
+
+    pub fn add_file(&self, url: &str, content: &[u8]) {
+        self.state().files.insert(url.to_string(), content.to_vec());
+    }
+
+    /// Returns the requests so far, like `GET /channels/1/messages`.
+    pub fn requests(&self) -> Vec<String> {
+        self.state().requests.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Records the request and returns the response if it's rate limited.
+    fn request<T>(&self, request: String) -> Option<Response<T>> {
+        let mut state = self.state();
+        state.requests.push(request);
+        if state.rate_limited == 0 {
+            return None;
+        }
+        state.rate_limited -= 1;
+        let rate_limit = RateLimitInfo {
+            retry_after: Some(RETRY_AFTER),
+            ..RateLimitInfo::default()
+        };
+        Some((
+            Err(discord_error(StatusCode::TOO_MANY_REQUESTS, 0)),
+            rate_limit,
+        ))
+    }
+}
+
+fn ok<T>(value: T) -> Response<T> {
+    (Ok(value), RateLimitInfo::default())
+}
+
+fn err<T>(status: StatusCode, code: isize) -> Response<T> {
+    (Err(discord_error(status, code)), RateLimitInfo::default())
+}
+
+#[async_trait]
+impl DiscordApi for FakeDiscord {
+    async fn get_guilds(&self, after: u64, limit: u64) -> Response<Vec<GuildInfo>> {
+        if let Some(res) = self.request("GET /users/@me/guilds".to_string()) {
+            return res;
+        }
+        let state = self.state();
+        let guilds = state.guilds.range(after + 1..).take(limit as usize);
+        ok(guilds.map(|(_, guild)| guild.clone()).collect())
+    }
+
+    async fn get_channels(&self, guild_id: u64) -> Response<Vec<GuildChannel>> {
+        if let Some(res) = self.request(format!("GET /guilds/{}/channels", guild_id)) {
+            return res;
+        }
+        let state = self.state();
+        let channels = state
+            .channels
+            .values()
+            .filter(|channel| *channel.guild_id.as_u64() == guild_id);
+        ok(channels.cloned().collect())
+    }
+
+    async fn get_member(&self, guild_id: u64, user_id: u64) -> Response<PartialMember> {
+        let request = format!("GET /guilds/{}/members/{}", guild_id, user_id);
+        if let Some(res) = self.request(request) {
+            return res;
+        }
+        match self.state().members.get(&(guild_id, user_id)) {
+            Some(roles) => ok(serde_json::from_value(json!({
+                "deaf": false,
+                "mute": false,
+                "joined_at": null,
+                "nick": null,
+                "roles": roles.iter().map(u64::to_string).collect::<Vec<_>>(),
+            }))
+            .unwrap()),
+            None => err(StatusCode::NOT_FOUND, UNKNOWN_MEMBER),
+        }
+    }
+
+    async fn get_messages(
+        &self,
+        channel_id: u64,
+        before: Option<u64>,
+        limit: u64,
+    ) -> Response<Vec<Message>> {
+        if let Some(res) = self.request(format!("GET /channels/{}/messages", channel_id)) {
+            return res;
+        }
+        let state = self.state();
+        if state.hidden.contains(&channel_id) {
+            return err(StatusCode::FORBIDDEN, MISSING_ACCESS);
+        }
+        let messages = state.messages.get(&channel_id);
+        let batch = messages
+            .into_iter()
+            .flat_map(|messages| messages.range(..before.unwrap_or(u64::MAX)).rev())
+            .take(limit.min(100) as usize);
+        ok(batch.map(|(_, msg)| msg.clone()).collect())
+    }
+
+    async fn delete_message(&self, channel_id: u64, message_id: u64) -> Response<()> {
+        let request = format!("DELETE /channels/{}/messages/{}", channel_id, message_id);
+        if let Some(res) = self.request(request) {
+            return res;
+        }
+        let mut state = self.state();
+        if state.hidden.contains(&channel_id) || state.read_only.contains(&channel_id) {
+            return err(StatusCode::FORBIDDEN, MISSING_PERMISSIONS);
+        }
+        let messages = state.messages.entry(channel_id).or_default();
+        match messages.remove(&message_id) {
+            Some(_) => ok(()),
+            None => err(StatusCode::NOT_FOUND, UNKNOWN_MESSAGE),
+        }
+    }
+
+    async fn delete_messages(&self, channel_id: u64, map: &Value) -> Response<()> {
+        let request = format!("POST /channels/{}/messages/bulk-delete", channel_id);
+        if let Some(res) = self.request(request) {
+            return res;
+        }
+        let mut state = self.state();
+        if state.hidden.contains(&channel_id) || state.read_only.contains(&channel_id) {
+            return err(StatusCode::FORBIDDEN, MISSING_PERMISSIONS);
+        }
+        let message_ids: Vec<u64> = match serde_json::from_value(map["messages"].clone()) {
+            Ok(message_ids) => message_ids,
+            Err(_) => return err(StatusCode::BAD_REQUEST, INVALID_FORM_BODY),
+        };
+        let two_weeks_ago = Utc::now() - chrono::Duration::weeks(2);
+        let too_old = message_ids
+            .iter()
+            .any(|id| MessageId(*id).created_at() < two_weeks_ago);
+        if state.reject_bulk_deletes || too_old || !(2..=100).contains(&message_ids.len()) {
+            return err(StatusCode::BAD_REQUEST, INVALID_FORM_BODY);
+        }
+        let messages = state.messages.entry(channel_id).or_default();
+        for id in message_ids {
+            messages.remove(&id);
+        }
+        ok(())
+    }
+
+    async fn download(&self, url: &str) -> Result<Vec<u8>> {
+        self.state().requests.push(format!("GET {}", url));
+        self.state()
+            .files
+            .get(url)
+            .cloned()
+            .context("404 Not Found")
+    }
+}
+
+/// Creates a message by a regular user without attachments.
+pub fn message(id: u64, channel_id: u64, time: DateTime<Utc>, pinned: bool) -> Message {
+    message_with(id, channel_id, time, json!({ "pinned": pinned }))
+}
+
+/// Creates a message like [`message`] with the given fields replaced, e.g.
+/// `json!({ "author": fake::author(3, true) })` for a bot's message.
+pub fn message_with(id: u64, channel_id: u64, time: DateTime<Utc>, fields: Value) -> Message {
+    let mut message = json!({
+        "id": id.to_string(),
+        "channel_id": channel_id.to_string(),
+        "author": author(2, false),
+        "content": "foo",
+        "timestamp": time.to_rfc3339(),
+        "edited_timestamp": null,
+        "tts": false,
+        "mention_everyone": false,
+        "mentions": [],
+        "mention_roles": [],
+        "attachments": [],
+        "embeds": [],
+        "pinned": false,
+        "type": 0,
+    });
+    if let (Some(message), Value::Object(fields)) = (message.as_object_mut(), fields) {
+        message.extend(fields);
+    }
+    serde_json::from_value(message).unwrap()
+}
+
+/// Creates the author of a message.
+pub fn author(id: u64, bot: bool) -> Value {
+    json!({
+        "id": id.to_string(),
+        "username": "user",
+        "discriminator": "0001",
+        "avatar": null,
+        "bot": bot,
+    })
+}
+
+/// Creates an attachment of a message in the given channel.
+pub fn attachment(id: u64, channel_id: u64, filename: &str, size: u64) -> Value {
+    let path = format!("attachments/{}/{}/{}", channel_id, id, filename);
+    json!({
+        "id": id.to_string(),
+        "filename": filename,
+        "size": size,
+        "url": format!("https://cdn.discordapp.com/{}", path),
+        "proxy_url": format!("https://media.discordapp.net/{}", path),
+        "height": null,
+        "width": null,
+    })
+}
+
+/// Creates the error serenity returns for an unsuccessful response.
+pub fn discord_error(status_code: StatusCode, code: isize) -> serenity::Error {
+    serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(ErrorResponse {
+        status_code,
+        url: "https://discord.com/api/v8/channels/1/messages"
+            .parse()
+            .unwrap(),
+        error: serde_json::from_value(json!({ "code": code, "message": "error" })).unwrap(),
+    })))
+}
@@ -0,0 +1,152 @@
+use futures::channel::oneshot;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response as HttpResponse, Server, StatusCode,
+};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::{json, Value};
+use serenity::http::HttpError;
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use super::{fake::FakeDiscord, DiscordApi, Response};
+
+/// A local HTTP server that stands in for the Discord API. It serves the
+/// endpoints the bot uses from a [`FakeDiscord`], so tests can go through the
+/// real HTTP client without network access. It stops when it's dropped.
+pub struct StandIn {
+    /// The base URL to pass to [`Client`](super::Client).
+    pub url: Url,
+    pub discord: Arc<FakeDiscord>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl StandIn {
+    /// Starts the server on a free port, it needs to run on a Tokio runtime.
+    pub fn start() -> Self {
+        let discord = Arc::new(FakeDiscord::default());
+        let service_discord = discord.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let discord = service_discord.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, discord.clone()))) }
+        }));
+        let url = Url::parse(&format!("http://{}/api/v6", server.local_addr())).unwrap();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = stopped.await;
+        }));
+        StandIn {
+            url,
+            discord,
+            shutdown: Some(shutdown),
+        }
+    }
+}
+
+impl Drop for StandIn {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    discord: Arc<FakeDiscord>,
+) -> Result<HttpResponse<Body>, Infallible> {
+    let path = req.uri().path().trim_start_matches("/api/v6").to_string();
+    let query: HashMap<String, u64> = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(key, value)| Some((key.to_string(), value.parse().ok()?)))
+        .collect();
+    let param = |key: &str, default: u64| query.get(key).copied().unwrap_or(default);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let ids: Vec<Option<u64>> = segments
+        .iter()
+        .map(|segment| segment.parse().ok())
+        .collect();
+
+    let response = match (req.method(), segments.as_slice(), ids.as_slice()) {
+        (&Method::GET, ["users", "@me", "guilds"], _) => respond(
+            discord
+                .get_guilds(param("after", 0), param("limit", 100))
+                .await,
+        ),
+        (&Method::GET, ["guilds", _, "channels"], [_, Some(guild_id), _]) => {
+            respond(discord.get_channels(*guild_id).await)
+        }
+        (&Method::GET, ["guilds", _, "members", _], [_, Some(guild_id), _, Some(user_id)]) => {
+            respond(discord.get_member(*guild_id, *user_id).await)
+        }
+        (&Method::GET, ["channels", _, "messages"], [_, Some(channel_id), _]) => respond(
+            discord
+                .get_messages(
+                    *channel_id,
+                    query.get("before").copied(),
+                    param("limit", 50),
+                )
+                .await,
+        ),
+        (
+            &Method::DELETE,
+            ["channels", _, "messages", _],
+            [_, Some(channel_id), _, Some(message_id)],
+        ) => respond(discord.delete_message(*channel_id, *message_id).await),
+        (&Method::POST, ["channels", _, "messages", "bulk-delete"], [_, Some(channel_id), ..]) => {
+            let channel_id = *channel_id;
+            let body = hyper::body::to_bytes(req.into_body()).await;
+            let map: Value = body
+                .ok()
+                .and_then(|body| serde_json::from_slice(&body).ok())
+                .unwrap_or_default();
+            respond(discord.delete_messages(channel_id, &map).await)
+        }
+        _ => HttpResponse::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(r#"{"code": 0, "message": "404: Not Found"}"#))
+            .unwrap(),
+    };
+    Ok(response)
+}
+
+/// Turns the response of the fake into one like Discord's. An empty result is
+/// a `204 No Content`, errors carry Discord's error code and rate limits their
+/// headers.
+fn respond<T: Serialize>((res, rate_limit): Response<T>) -> HttpResponse<Body> {
+    let mut builder = HttpResponse::builder();
+    if let Some(retry_after) = rate_limit.retry_after {
+        builder = builder
+            .header("retry-after", retry_after.as_secs_f64().to_string())
+            .header("x-ratelimit-global", rate_limit.global.to_string());
+    }
+    let (status, body) = match res {
+        Ok(value) => match serde_json::to_value(&value).unwrap() {
+            Value::Null => (StatusCode::NO_CONTENT, None),
+            value => (StatusCode::OK, Some(value)),
+        },
+        Err(serenity::Error::Http(err)) => match *err {
+            HttpError::UnsuccessfulRequest(response) => (
+                StatusCode::from_u16(response.status_code.as_u16()).unwrap(),
+                Some(json!({
+                    "code": response.error.code,
+                    "message": response.error.message,
+                })),
+            ),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, None),
+        },
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, None),
+    };
+    match body {
+        Some(body) => builder
+            .status(status)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string())),
+        None => builder.status(status).body(Body::empty()),
+    }
+    .unwrap()
+}
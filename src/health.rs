@@ -0,0 +1,124 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Mutex;
+
+/// Tracks whether the daemon is ready and healthy, for the `/readyz` and
+/// `/healthz` endpoints.
+#[derive(Debug)]
+pub struct Health {
+    /// The number of failed runs in a row after which the daemon is unhealthy.
+    max_failures: u32,
+    /// How long a run may take, or the daemon may oversleep its next run,
+    /// before it's considered stuck.
+    stuck_after: Duration,
+    state: Mutex<HealthState>,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    /// Whether the token was accepted and the bot is in at least one guild.
+    ready: bool,
+    /// The loop is stuck if it didn't make progress by then.
+    deadline: Option<DateTime<Utc>>,
+    consecutive_failures: u32,
+}
+
+impl Health {
+    pub fn new(max_failures: u32, stuck_after: Duration) -> Self {
+        Health {
+            max_failures,
+            stuck_after,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.state.lock().unwrap().ready = ready;
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state.lock().unwrap().ready
+    }
+
+    /// Records that the loop sleeps until the next run is due.
+    pub fn waiting_until(&self, due: DateTime<Utc>) {
+        self.state.lock().unwrap().deadline = Some(due + self.stuck_after);
+    }
+
+    pub fn run_started(&self, now: DateTime<Utc>) {
+        self.state.lock().unwrap().deadline = Some(now + self.stuck_after);
+    }
+
+    pub fn run_finished(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            state.consecutive_failures = 0;
+        } else {
+            state.consecutive_failures += 1;
+        }
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.state.lock().unwrap().consecutive_failures
+    }
+
+    /// Returns why the daemon is unhealthy, if it is.
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), String> {
+        let state = self.state.lock().unwrap();
+        if let Some(deadline) = state.deadline.filter(|deadline| *deadline < now) {
+            return Err(format!(
+                "no progress since {}",
+                (deadline - self.stuck_after).to_rfc3339()
+            ));
+        }
+        if self.max_failures > 0 && state.consecutive_failures >= self.max_failures {
+            return Err(format!(
+                "{} runs failed in a row",
+                state.consecutive_failures
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_stuck() {
+        let health = Health::new(3, Duration::hours(1));
+        let now = Utc::now();
+        assert!(health.check(now).is_ok());
+
+        health.run_started(now);
+        assert!(health.check(now + Duration::minutes(59)).is_ok());
+        assert!(health.check(now + Duration::minutes(61)).is_err());
+
+        health.run_finished(true);
+        health.waiting_until(now + Duration::days(1));
+        assert!(health.check(now + Duration::hours(2)).is_ok());
+        assert!(health
+            .check(now + Duration::days(1) + Duration::hours(2))
+            .is_err());
+    }
+
+    #[test]
+    fn test_health_failures() {
+        let health = Health::new(2, Duration::hours(1));
+        let now = Utc::now();
+        health.run_finished(false);
+        assert!(health.check(now).is_ok());
+        health.run_finished(false);
+        assert_eq!(health.check(now), Err("2 runs failed in a row".to_string()));
+        health.run_finished(true);
+        assert!(health.check(now).is_ok());
+    }
+
+    #[test]
+    fn test_ready() {
+        let health = Health::new(3, Duration::hours(1));
+        assert!(!health.is_ready());
+        health.set_ready(true);
+        assert!(health.is_ready());
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{
+    kv::{self, VisitSource},
+    Log, Metadata, Record,
+};
+use serde_json::{json, Map, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::config::EnvFile;
+
+/// The target of the audit events, one for each deleted message.
+pub const AUDIT_TARGET: &str = "audit";
+
+/// Wraps `env_logger` to optionally format records as JSON and to write audit
+/// events to a separate file.
+struct Logger {
+    inner: env_logger::Logger,
+    /// The file audit events are appended to, instead of the regular log.
+    audit: Option<Mutex<File>>,
+}
+
+/// Installs the logger. It's configured with `RUST_LOG` like `env_logger`,
+/// `LOG_FORMAT=json` and `AUDIT_LOG_PATH`, from the environment or the `.env`
+/// file.
+pub fn init(env_file: &EnvFile) -> Result<()> {
+    let json = match env_file.var("LOG_FORMAT").as_deref() {
+        Some("json") => true,
+        Some("text") | None => false,
+        Some(other) => anyhow::bail!("Unknown LOG_FORMAT {:?}, use text or json", other),
+    };
+    let audit = match env_file.var("AUDIT_LOG_PATH") {
+        Some(path) => Some(Mutex::new(open_audit_log(Path::new(&path))?)),
+        None => None,
+    };
+
+    let mut builder = env_logger::Builder::new();
+    if let Some(filters) = env_file.var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if let Some(write_style) = env_file.var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&write_style);
+    }
+    if json {
+        builder.format(|buf, record| writeln!(buf, "{}", to_json(record)));
+    }
+    let inner = builder.build();
+    let max_level = match audit {
+        Some(_) => inner.filter().max(log::LevelFilter::Info),
+        None => inner.filter(),
+    };
+    log::set_boxed_logger(Box::new(Logger { inner, audit }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+fn open_audit_log(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open {}", path.display()))
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        (self.audit.is_some() && metadata.target() == AUDIT_TARGET) || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        match &self.audit {
+            Some(audit) if record.target() == AUDIT_TARGET => {
+                let mut file = audit.lock().unwrap();
+                if let Err(e) = writeln!(file, "{}", to_json(record)) {
+                    // Don't log this, it might end up here again.
+                    let _ = writeln!(io::stderr(), "Could not write audit event: {}", e);
+                }
+            }
+            _ if self.inner.matches(record) => self.inner.log(record),
+            _ => {}
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+        if let Some(audit) = &self.audit {
+            let _ = audit.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Formats the record as a single line of JSON, including its key-values.
+fn to_json(record: &Record) -> Value {
+    let mut object = Map::new();
+    object.insert("timestamp".to_string(), json!(Utc::now().to_rfc3339()));
+    object.insert("level".to_string(), json!(record.level().as_str()));
+    object.insert("target".to_string(), json!(record.target()));
+    object.insert("message".to_string(), json!(record.args().to_string()));
+    let _ = record.key_values().visit(&mut JsonVisitor(&mut object));
+    Value::Object(object)
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| json!(value.to_string()));
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, sync::Arc};
+
+    #[test]
+    fn test_to_json() {
+        let kvs: &[(&str, kv::Value)] = &[
+            ("guild_id", kv::Value::from_display(&1234)),
+            ("pinned", kv::Value::from(true)),
+        ];
+        let record = Record::builder()
+            .target(AUDIT_TARGET)
+            .level(log::Level::Info)
+            .args(format_args!("Deleted message"))
+            .key_values(&kvs)
+            .build();
+        let value = to_json(&record);
+        assert_eq!(value["target"], "audit");
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["message"], "Deleted message");
+        assert_eq!(value["guild_id"], "1234");
+        assert_eq!(value["pinned"], true);
+    }
+
+    /// Collects the records as JSON.
+    #[derive(Default)]
+    struct Capture(Mutex<Vec<Value>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(to_json(record));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_ids_are_logged_as_strings() {
+        // Snowflakes exceed the integers JSON parsers can represent exactly
+        let msg_id: u64 = 1_234_567_890_123_456_789;
+        let capture = Capture::default();
+        // No logger is installed in tests, so nothing else is affected
+        log::set_max_level(log::LevelFilter::Warn);
+        log::warn!(
+            logger: capture,
+            guild_id:% = 1u64, message_id:% = msg_id;
+            "Could not delete message {}", msg_id
+        );
+        let records = capture.0.lock().unwrap();
+        assert_eq!(records[0]["guild_id"], "1");
+        assert_eq!(records[0]["message_id"], "1234567890123456789");
+    }
+
+    #[test]
+    fn test_audit_events_go_to_audit_log_only() {
+        let path = env::temp_dir().join(format!(
+            "discord-retention-bot-audit-{}.jsonl",
+            std::process::id()
+        ));
+        let logged = Arc::new(Mutex::new(vec![]));
+        let inner_logged = logged.clone();
+        let inner = env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Info)
+            .format(move |_, record| {
+                inner_logged
+                    .lock()
+                    .unwrap()
+                    .push(record.target().to_string());
+                Ok(())
+            })
+            .build();
+        let logger = Logger {
+            inner,
+            audit: Some(Mutex::new(open_audit_log(&path).unwrap())),
+        };
+
+        for target in &[AUDIT_TARGET, "discord_retention_bot::bot"] {
+            logger.log(
+                &Record::builder()
+                    .target(target)
+                    .level(log::Level::Info)
+                    .args(format_args!("Deleted message"))
+                    .build(),
+            );
+        }
+        logger.flush();
+
+        assert_eq!(*logged.lock().unwrap(), vec!["discord_retention_bot::bot"]);
+        let audit_log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = audit_log.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let event: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(event["target"], AUDIT_TARGET);
+        fs::remove_file(&path).unwrap();
+    }
+}
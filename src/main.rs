@@ -1,39 +1,285 @@
-#![feature(async_closure)]
-
 use anyhow::{Context, Result};
-use chrono::Duration;
-use dotenv::dotenv;
-use log::info;
-use serenity::{client::validate_token, http::client::Http};
-use std::env;
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use std::{path::PathBuf, process, sync::Arc};
+use structopt::StructOpt;
 use tokio::time;
 
+mod archive;
 mod bot;
 mod config;
+mod discord;
+mod health;
+mod logging;
+mod metrics;
+mod ratelimit;
+mod schedule;
+mod server;
+mod state;
+
+use health::Health;
+use state::State;
+
+/// Exit code of `run --once` if some guilds or channels could not be
+/// processed.
+const EXIT_PARTIAL_FAILURE: i32 = 2;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about)]
+struct Opt {
+    /// Path to a TOML configuration file, environment variables take
+    /// precedence over its values
+    #[structopt(long, env = "CONFIG_PATH", parse(from_os_str), global = true)]
+    config: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Delete expired messages on the configured schedule, or only once with
+    /// `--once`
+    Run {
+        /// Do a single pass and exit with 0 on success, 1 if it could not
+        /// run at all and 2 if some guilds or channels failed
+        #[structopt(long)]
+        once: bool,
+    },
+    /// Delete expired messages on the configured schedule (default)
+    Daemon,
+    /// Validate the configuration and print the resolved rules
+    CheckConfig,
+    /// List all guilds and channels with their effective retention
+    ListChannels,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    dotenv().ok();
-    env_logger::init();
+    let env_file = config::EnvFile::load();
+    logging::init(&env_file)?;
+    let opt = Opt::from_args();
+    // The environment takes precedence, but the path can be in `.env` too
+    let config_path = opt
+        .config
+        .or_else(|| env_file.var("CONFIG_PATH").map(PathBuf::from));
 
-    let discord_token = env::var("DISCORD_TOKEN").context("DISCORD_TOKEN is unset")?;
-    let channel_retention = env::var("CHANNEL_RETENTION")
-        .context("CHANNEL_RETENTION is unset")
-        .and_then(config::parse_channel_retention)
-        .context("Could not parse channel retention")?;
-    let delete_pinned = env::var("DELETE_PINNED")
-        .map(|val| val == "true")
-        .unwrap_or(false);
-    validate_token(&discord_token).context("Token is invalid")?;
-
-    let client = Http::new_with_token(&discord_token);
+    match opt.command.unwrap_or(Command::Daemon) {
+        Command::Run { once: true } => {
+            let config = config::Config::load(config_path.as_deref(), &env_file)
+                .context("Could not load configuration")?;
+            let client =
+                discord::Client::new(&config.discord_token, config.discord_api_url.as_ref());
+            let state = State::load(config.state_path.as_deref());
+            let summary = bot::run(&client, &config, &state).await?;
+            if !summary.is_success() {
+                warn!(
+                    "{} of {} guilds and {} of {} channels failed, {} deletions failed",
+                    summary.failed_guilds,
+                    summary.guilds,
+                    summary.failed_channels,
+                    summary.channels,
+                    summary.failed_deletions
+                );
+                process::exit(EXIT_PARTIAL_FAILURE);
+            }
+            Ok(())
+        }
+        Command::Run { once: false } | Command::Daemon => {
+            let config_watcher = config::ConfigWatcher::new(config_path, env_file)?;
+            daemon(config_watcher).await
+        }
+        Command::CheckConfig => {
+            let config = config::Config::load(config_path.as_deref(), &env_file)
+                .context("Could not load configuration")?;
+            print!("{}", config.retention);
+            Ok(())
+        }
+        Command::ListChannels => {
+            let config = config::Config::load(config_path.as_deref(), &env_file)
+                .context("Could not load configuration")?;
+            let client =
+                discord::Client::new(&config.discord_token, config.discord_api_url.as_ref());
+            bot::list_channels(&client, &config).await
+        }
+    }
+}
 
-    let mut interval = time::interval(Duration::minutes(1).to_std()?);
-    interval.tick().await; // the first tick completes immediately
+async fn daemon(mut config_watcher: config::ConfigWatcher) -> Result<()> {
+    let mut config = config_watcher
+        .load()
+        .context("Could not load configuration")?;
+    let mut client = discord::Client::new(&config.discord_token, config.discord_api_url.as_ref());
+    let mut state = State::load(config.state_path.as_deref());
+    let health = Arc::new(Health::new(
+        config.health_max_failures,
+        config.health_stuck_after,
+    ));
+    if let Some(metrics_addr) = config.metrics_addr {
+        server::spawn(metrics_addr, health.clone()).context("Could not start HTTP server")?;
+    }
+    check_access(&client, &health).await?;
+    info!("Sweeping {}", config.schedule);
 
+    let mut next_run = config.schedule.first_run(Utc::now());
+    let mut last_started: Option<DateTime<Utc>> = None;
     loop {
-        bot::run(&client, &channel_retention, delete_pinned).await?;
-        info!("Sleeping until the time interval is up");
-        interval.tick().await;
+        let due = match next_run {
+            Some(due) => due,
+            None => {
+                warn!("The schedule has no future runs, exiting");
+                return Ok(());
+            }
+        };
+        info!(
+            "Next run is due at {}",
+            due.with_timezone(&config.schedule.timezone())
+        );
+        health.waiting_until(due);
+        if let Ok(delay) = (due - Utc::now()).to_std() {
+            time::delay_for(delay).await;
+        }
+
+        // Changes made while sleeping already apply to this run
+        if let Some(new_config) = config_watcher.reload() {
+            if new_config.discord_token != config.discord_token
+                || new_config.discord_api_url != config.discord_api_url
+            {
+                let new_client = discord::Client::new(
+                    &new_config.discord_token,
+                    new_config.discord_api_url.as_ref(),
+                );
+                if let Err(e) = check_access(&new_client, &health).await {
+                    warn!(
+                        "Keeping the previous configuration, the new one was rejected: {:?}",
+                        e
+                    );
+                    continue;
+                }
+                client = new_client;
+            }
+            warn_restart_required(&config, &new_config);
+            if new_config.state_path.as_deref() != state.path() {
+                state = State::load(new_config.state_path.as_deref());
+            }
+            let schedule_changed = new_config.schedule.to_string() != config.schedule.to_string();
+            config = new_config;
+            if schedule_changed {
+                info!("Sweeping {}", config.schedule);
+                next_run = match last_started {
+                    Some(started) => config.schedule.next_after(started),
+                    None => config.schedule.first_run(Utc::now()),
+                };
+                continue;
+            }
+        }
+
+        let started = Utc::now();
+        last_started = Some(started);
+        health.run_started(started);
+        let res = bot::run(&client, &config, &state).await;
+        health.run_finished(matches!(&res, Ok(summary) if summary.is_success()));
+        let failures = health.consecutive_failures();
+        let retry_at = match res {
+            Ok(summary) => {
+                health.set_ready(summary.guilds > 0);
+                if failures > 0 {
+                    warn!("Run failed partially, {} failed runs in a row", failures);
+                }
+                None
+            }
+            Err(e) if bot::is_fatal(&e) => return Err(e).context("Discord rejected the token"),
+            Err(e) => {
+                let delay = failure_backoff(failures);
+                error!(
+                    "Run failed, {} failed runs in a row, retrying in {}s: {:?}",
+                    failures,
+                    delay.num_seconds(),
+                    e
+                );
+                Some(Utc::now() + delay)
+            }
+        };
+
+        next_run = config.schedule.next_after(started);
+        let finished = Utc::now();
+        if let Some(due) = next_run.filter(|due| *due < finished) {
+            // Missed slots are skipped, the next run starts right away.
+            warn!(
+                "Run took {}s and overlapped its next slot at {}, starting the next run now",
+                (finished - started).num_seconds(),
+                due.with_timezone(&config.schedule.timezone())
+            );
+            next_run = Some(finished);
+        }
+        if retry_at.is_some() {
+            next_run = retry_at;
+        }
+    }
+}
+
+/// Returns how long to wait before retrying after the given number of failed
+/// runs in a row.
+fn failure_backoff(failures: u32) -> Duration {
+    let max = Duration::minutes(15);
+    let exponent = failures.saturating_sub(1).min(10);
+    (Duration::seconds(30) * 2i32.pow(exponent)).min(max)
+}
+
+/// Warns about changed settings that only apply at startup.
+fn warn_restart_required(config: &config::Config, new_config: &config::Config) {
+    let mut changed = vec![];
+    if new_config.metrics_addr != config.metrics_addr {
+        changed.push("METRICS_ADDR");
+    }
+    if new_config.health_max_failures != config.health_max_failures {
+        changed.push("HEALTH_MAX_FAILURES");
+    }
+    if new_config.health_stuck_after != config.health_stuck_after {
+        changed.push("HEALTH_STUCK_AFTER");
+    }
+    if !changed.is_empty() {
+        warn!(
+            "{} changed, restart the bot to apply it",
+            changed.join(", ")
+        );
+    }
+}
+
+/// Checks that Discord accepts the token and marks the daemon as ready if the
+/// bot is in at least one guild.
+/// Only an invalid token is an error, other failures are logged and the
+/// daemon stays unready until a run succeeds.
+async fn check_access(client: &discord::Client, health: &Health) -> Result<()> {
+    match bot::has_guilds(client).await {
+        Ok(has_guilds) => {
+            if !has_guilds {
+                warn!("The bot isn't in any guild yet");
+            }
+            health.set_ready(has_guilds);
+        }
+        Err(e) if bot::is_fatal(&e) => return Err(e).context("Discord rejected the token"),
+        Err(e) => {
+            health.set_ready(false);
+            warn!(
+                "Could not list guilds, trying again with the first run: {:?}",
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_backoff() {
+        assert_eq!(failure_backoff(1), Duration::seconds(30));
+        assert_eq!(failure_backoff(2), Duration::minutes(1));
+        assert_eq!(failure_backoff(5), Duration::minutes(8));
+        assert_eq!(failure_backoff(6), Duration::minutes(15));
+        assert_eq!(failure_backoff(100), Duration::minutes(15));
     }
 }
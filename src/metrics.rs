@@ -0,0 +1,139 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_counter_vec, register_gauge, register_gauge_vec, register_histogram,
+    register_int_counter_vec, CounterVec, Encoder, Gauge, GaugeVec, Histogram, IntCounterVec,
+    TextEncoder,
+};
+
+/// The labels of per-channel metrics. Names are included to make alerts
+/// readable, ids to keep channels apart that share a name.
+const CHANNEL_LABELS: &[&str] = &["guild_id", "guild", "channel_id", "channel"];
+
+pub static MESSAGES_DELETED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "discord_retention_messages_deleted_total",
+        "Messages deleted",
+        CHANNEL_LABELS
+    )
+    .unwrap()
+});
+
+pub static MESSAGES_SCANNED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "discord_retention_messages_scanned_total",
+        "Messages fetched to check their age",
+        CHANNEL_LABELS
+    )
+    .unwrap()
+});
+
+pub static API_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "discord_retention_api_errors_total",
+        "Failed requests to the Discord API, including ones that were retried",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMIT_WAIT: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "discord_retention_rate_limit_wait_seconds_total",
+        "Time spent waiting for rate limits and backing off",
+        &["guild_id"]
+    )
+    .unwrap()
+});
+
+pub static SWEEP_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "discord_retention_sweep_duration_seconds",
+        "Duration of a sweep over all guilds",
+        vec![1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14400.0]
+    )
+    .unwrap()
+});
+
+pub static LAST_SUCCESSFUL_SWEEP: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "discord_retention_last_successful_sweep_timestamp_seconds",
+        "Time of the last sweep without failed guilds or channels"
+    )
+    .unwrap()
+});
+
+pub static CHANNEL_LAST_SUCCESS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "discord_retention_channel_last_success_timestamp_seconds",
+        "Time the channel was last swept without errors",
+        CHANNEL_LABELS
+    )
+    .unwrap()
+});
+
+pub static CHANNEL_RETENTION: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "discord_retention_channel_retention_seconds",
+        "Configured maximum age of the messages in the channel",
+        CHANNEL_LABELS
+    )
+    .unwrap()
+});
+
+pub static CHANNEL_KEEP: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "discord_retention_channel_keep_messages",
+        "Configured number of the newest messages that are kept in the channel",
+        CHANNEL_LABELS
+    )
+    .unwrap()
+});
+
+/// The label values of a channel for per-channel metrics.
+pub struct ChannelLabels([String; 4]);
+
+impl ChannelLabels {
+    pub fn new(guild_id: u64, guild: &str, channel_id: u64, channel: &str) -> Self {
+        ChannelLabels([
+            guild_id.to_string(),
+            guild.to_string(),
+            channel_id.to_string(),
+            channel.to_string(),
+        ])
+    }
+
+    pub fn values(&self) -> [&str; 4] {
+        let [guild_id, guild, channel_id, channel] = &self.0;
+        [guild_id, guild, channel_id, channel]
+    }
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn encode() -> Vec<u8> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let labels = ChannelLabels::new(1, "guild", 2, "general");
+        MESSAGES_DELETED
+            .with_label_values(&labels.values())
+            .inc_by(3);
+        let output = String::from_utf8(encode()).unwrap();
+        assert!(
+            output.contains(
+                r#"discord_retention_messages_deleted_total{channel="general",channel_id="2",guild="guild",guild_id="1"} 3"#
+            ),
+            "{}",
+            output
+        );
+    }
+}
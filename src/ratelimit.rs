@@ -0,0 +1,477 @@
+use log::{debug, warn};
+use rand::Rng;
+use reqwest::{header::HeaderMap, StatusCode};
+use serenity::http::HttpError;
+use std::{
+    collections::HashMap,
+    future::Future,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+use crate::metrics;
+
+/// How often a single request is retried before giving up.
+const MAX_RETRIES: u32 = 5;
+
+/// The delay before the first retry, it doubles with every further attempt.
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// The longest delay between two attempts.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// The rate limit Discord reported in the headers of a response.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RateLimitInfo {
+    /// The requests left in the bucket of the route.
+    pub remaining: Option<u64>,
+    /// The time until the bucket of the route is refilled.
+    pub reset_after: Option<Duration>,
+    /// How long to wait before retrying a rate limited (429) request.
+    pub retry_after: Option<Duration>,
+    /// Whether the rate limit applies to all routes.
+    pub global: bool,
+}
+
+impl RateLimitInfo {
+    pub fn from_response(status: StatusCode, headers: &HeaderMap) -> Self {
+        let reset_after = header(headers, "x-ratelimit-reset-after").and_then(seconds);
+        let rate_limited = status == StatusCode::TOO_MANY_REQUESTS;
+        RateLimitInfo {
+            remaining: header(headers, "x-ratelimit-remaining"),
+            reset_after,
+            retry_after: match header(headers, "retry-after").and_then(seconds) {
+                Some(retry_after) if rate_limited => Some(retry_after),
+                _ if rate_limited => reset_after,
+                _ => None,
+            },
+            global: header(headers, "x-ratelimit-global").unwrap_or(false),
+        }
+    }
+}
+
+fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn seconds(secs: f64) -> Option<Duration> {
+    if secs.is_finite() && secs >= 0.0 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+/// Waits for the rate limits Discord reports, retries requests that were rate
+/// limited or failed with a server error and keeps track of how long each
+/// guild waited for that.
+///
+/// A route is blocked until its bucket is refilled once it's exhausted, for
+/// the `Retry-After` of a 429 and while it backs off from a server error. A
+/// global rate limit blocks all routes. All the time spent waiting is charged
+/// to the budget of the sweep, once it's used up failed requests are no longer
+/// retried.
+#[derive(Debug)]
+pub struct RateLimits {
+    /// Routes that are rate limited or backing off and the time until which
+    /// they're blocked.
+    blocked: Mutex<HashMap<String, Instant>>,
+    /// The time until which all routes are blocked by a global rate limit.
+    blocked_globally: Mutex<Option<Instant>>,
+    /// The time the sweep may still spend waiting.
+    budget: Mutex<Duration>,
+    /// The time spent waiting per guild, `None` for requests outside guilds.
+    waited: Mutex<HashMap<Option<u64>, Duration>>,
+}
+
+impl RateLimits {
+    pub fn new(budget: Duration) -> Self {
+        RateLimits {
+            blocked: Mutex::new(HashMap::new()),
+            blocked_globally: Mutex::new(None),
+            budget: Mutex::new(budget),
+            waited: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Performs the request once the route isn't blocked anymore, retrying it
+    /// as long as it fails with a retryable error and the budget allows it.
+    /// Rate limited requests are retried after their `Retry-After`, others
+    /// with jittered exponential back-off.
+    pub async fn retry<T, F, Fut>(
+        &self,
+        guild_id: Option<u64>,
+        route: &str,
+        mut request: F,
+    ) -> serenity::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = (serenity::Result<T>, RateLimitInfo)>,
+    {
+        let mut attempt = 0;
+        loop {
+            self.wait_for(guild_id, route).await;
+            let (res, rate_limit) = request().await;
+            self.update(route, &rate_limit);
+            if let Err(e) = &res {
+                metrics::API_ERRORS
+                    .with_label_values(&[error_kind(e)])
+                    .inc();
+            }
+            let err = match res {
+                Err(e) if attempt < MAX_RETRIES && is_retryable(&e) => e,
+                res => return res,
+            };
+
+            let delay = rate_limit.retry_after.unwrap_or_else(|| backoff(attempt));
+            if !self.has_budget(delay) {
+                warn!(
+                    "Retry budget of this sweep is exhausted, giving up on {}",
+                    route
+                );
+                return Err(err);
+            }
+            warn!(
+                "Request to {} failed, retrying in {}ms: {}",
+                route,
+                delay.as_millis(),
+                err
+            );
+            self.block(route, delay);
+            attempt += 1;
+        }
+    }
+
+    /// Returns the time the given guild spent waiting for rate limits.
+    pub fn waited(&self, guild_id: Option<u64>) -> Duration {
+        let waited = self.waited.lock().unwrap();
+        waited.get(&guild_id).copied().unwrap_or_default()
+    }
+
+    /// Returns the time all guilds spent waiting for rate limits.
+    pub fn total_waited(&self) -> Duration {
+        self.waited.lock().unwrap().values().sum()
+    }
+
+    /// Sleeps until neither the route nor all routes are blocked anymore.
+    async fn wait_for(&self, guild_id: Option<u64>, route: &str) {
+        loop {
+            let blocked_until = self.blocked.lock().unwrap().get(route).copied();
+            let blocked_globally = *self.blocked_globally.lock().unwrap();
+            let delay = match blocked_until.max(blocked_globally) {
+                Some(blocked_until) => blocked_until.saturating_duration_since(Instant::now()),
+                None => return,
+            };
+            if delay == Duration::from_secs(0) {
+                return;
+            }
+            debug!("Waiting {}ms for {}", delay.as_millis(), route);
+            time::delay_for(delay).await;
+            self.record_wait(guild_id, delay);
+        }
+    }
+
+    fn record_wait(&self, guild_id: Option<u64>, delay: Duration) {
+        *self.waited.lock().unwrap().entry(guild_id).or_default() += delay;
+        let mut budget = self.budget.lock().unwrap();
+        *budget = budget.saturating_sub(delay);
+        let guild_label = guild_id.map(|id| id.to_string()).unwrap_or_default();
+        metrics::RATE_LIMIT_WAIT
+            .with_label_values(&[&guild_label])
+            .inc_by(delay.as_secs_f64());
+    }
+
+    /// Blocks the route or all routes according to the rate limit of a
+    /// response.
+    fn update(&self, route: &str, rate_limit: &RateLimitInfo) {
+        match *rate_limit {
+            RateLimitInfo {
+                retry_after: Some(retry_after),
+                global: true,
+                ..
+            } => {
+                let blocked_until = Instant::now() + retry_after;
+                let mut blocked_globally = self.blocked_globally.lock().unwrap();
+                *blocked_globally = (*blocked_globally).max(Some(blocked_until));
+            }
+            RateLimitInfo {
+                retry_after: Some(delay),
+                ..
+            }
+            | RateLimitInfo {
+                remaining: Some(0),
+                reset_after: Some(delay),
+                ..
+            } => self.block(route, delay),
+            _ => {}
+        }
+    }
+
+    fn block(&self, route: &str, delay: Duration) {
+        let blocked_until = Instant::now() + delay;
+        let mut blocked = self.blocked.lock().unwrap();
+        let entry = blocked.entry(route.to_string()).or_insert(blocked_until);
+        *entry = (*entry).max(blocked_until);
+    }
+
+    fn has_budget(&self, delay: Duration) -> bool {
+        *self.budget.lock().unwrap() >= delay
+    }
+}
+
+/// Returns the HTTP status of the error, if the request got a response.
+fn status(err: &serenity::Error) -> Option<u16> {
+    match err {
+        serenity::Error::Http(http_error) => match http_error.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => Some(response.status_code.as_u16()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns whether the request timed out, like a server error it's retried.
+fn is_timeout(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(http_error) => {
+            matches!(http_error.as_ref(), HttpError::Request(e) if e.is_timeout())
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether the request might succeed if it's retried later.
+fn is_retryable(err: &serenity::Error) -> bool {
+    is_timeout(err) || status(err).is_some_and(is_retryable_status)
+}
+
+/// Names the kind of the error for the metrics.
+fn error_kind(err: &serenity::Error) -> &'static str {
+    match status(err) {
+        Some(429) => "rate_limited",
+        Some(403) => "forbidden",
+        Some(404) => "not_found",
+        Some(500..=599) => "server_error",
+        Some(_) => "client_error",
+        None if is_timeout(err) => "timeout",
+        None => match err {
+            serenity::Error::Http(_) => "request",
+            _ => "other",
+        },
+    }
+}
+
+fn is_retryable_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+/// Returns a random delay between half and all of the exponential back-off
+/// for the given attempt, so concurrent retries don't line up.
+fn backoff(attempt: u32) -> Duration {
+    let max = BASE_DELAY
+        .checked_mul(2u32.saturating_pow(attempt))
+        .unwrap_or(MAX_DELAY)
+        .min(MAX_DELAY);
+    let half = max / 2;
+    half + rand::thread_rng()
+        .gen_range(Duration::from_secs(0), max - half + Duration::from_nanos(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use serde_json::json;
+    use serenity::http::error::ErrorResponse;
+    use std::cell::Cell;
+
+    fn error(status_code: StatusCode) -> serenity::Error {
+        serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(ErrorResponse {
+            status_code,
+            url: "https://discord.com/api/v8/channels/1/messages"
+                .parse()
+                .unwrap(),
+            error: serde_json::from_value(json!({ "code": 0, "message": "error" })).unwrap(),
+        })))
+    }
+
+    fn server_error() -> serenity::Error {
+        error(StatusCode::BAD_GATEWAY)
+    }
+
+    #[test]
+    fn test_is_retryable_status() {
+        assert!(is_retryable_status(429));
+        assert!(is_retryable_status(502));
+        assert!(!is_retryable_status(403));
+        assert!(!is_retryable_status(404));
+    }
+
+    #[test]
+    fn test_backoff() {
+        for attempt in 0..10 {
+            let delay = backoff(attempt);
+            let max = (BASE_DELAY * 2u32.pow(attempt)).min(MAX_DELAY);
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let rate_limits = RateLimits::new(Duration::from_secs(60));
+        let attempts = Cell::new(0);
+        let res = rate_limits
+            .retry(Some(1), "GET /test", || {
+                attempts.set(attempts.get() + 1);
+                async {
+                    match attempts.get() {
+                        1 => (Err(server_error()), RateLimitInfo::default()),
+                        _ => (Ok(()), RateLimitInfo::default()),
+                    }
+                }
+            })
+            .await;
+        assert!(res.is_ok());
+        assert_eq!(attempts.get(), 2);
+        assert!(rate_limits.waited(Some(1)) >= BASE_DELAY / 2);
+        assert_eq!(rate_limits.waited(None), Duration::from_secs(0));
+    }
+
+    #[tokio::test]
+    async fn test_timeouts_are_retryable() {
+        // The server accepts the connection but never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let err = serenity::Error::from(http.get(&url).send().await.unwrap_err());
+        assert!(is_retryable(&err));
+        assert_eq!(error_kind(&err), "timeout");
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        let rate_limits = RateLimits::new(Duration::from_millis(100));
+        let attempts = Cell::new(0);
+        let res: serenity::Result<()> = rate_limits
+            .retry(None, "GET /test", || {
+                attempts.set(attempts.get() + 1);
+                async { (Err(server_error()), RateLimitInfo::default()) }
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_rate_limit_info() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-reset-after", HeaderValue::from_static("1.5"));
+        let info = RateLimitInfo::from_response(StatusCode::OK, &headers);
+        assert_eq!(info.remaining, Some(0));
+        assert_eq!(info.reset_after, Some(Duration::from_millis(1500)));
+        assert_eq!(info.retry_after, None);
+
+        let info = RateLimitInfo::from_response(StatusCode::TOO_MANY_REQUESTS, &headers);
+        assert_eq!(info.retry_after, Some(Duration::from_millis(1500)));
+        assert!(!info.global);
+
+        headers.insert("retry-after", HeaderValue::from_static("3"));
+        headers.insert("x-ratelimit-global", HeaderValue::from_static("true"));
+        let info = RateLimitInfo::from_response(StatusCode::TOO_MANY_REQUESTS, &headers);
+        assert_eq!(info.retry_after, Some(Duration::from_secs(3)));
+        assert!(info.global);
+
+        let info = RateLimitInfo::from_response(StatusCode::OK, &HeaderMap::new());
+        assert_eq!(info, RateLimitInfo::default());
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let rate_limits = RateLimits::new(Duration::from_secs(60));
+        let attempts = Cell::new(0);
+        let res = rate_limits
+            .retry(Some(1), "GET /test", || {
+                attempts.set(attempts.get() + 1);
+                async {
+                    match attempts.get() {
+                        1 => (
+                            Err(error(StatusCode::TOO_MANY_REQUESTS)),
+                            RateLimitInfo {
+                                retry_after: Some(Duration::from_millis(50)),
+                                ..RateLimitInfo::default()
+                            },
+                        ),
+                        _ => (Ok(()), RateLimitInfo::default()),
+                    }
+                }
+            })
+            .await;
+        assert!(res.is_ok());
+        assert_eq!(attempts.get(), 2);
+        let waited = rate_limits.waited(Some(1));
+        assert!(waited >= Duration::from_millis(40) && waited < BASE_DELAY / 2);
+        assert_eq!(
+            *rate_limits.budget.lock().unwrap(),
+            Duration::from_secs(60) - waited
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_bucket() {
+        let rate_limits = RateLimits::new(Duration::from_secs(60));
+        let exhausted = RateLimitInfo {
+            remaining: Some(0),
+            reset_after: Some(Duration::from_millis(50)),
+            ..RateLimitInfo::default()
+        };
+        let res = rate_limits
+            .retry(Some(1), "GET /test", || async { (Ok(()), exhausted) })
+            .await;
+        assert!(res.is_ok());
+        assert_eq!(rate_limits.waited(Some(1)), Duration::from_secs(0));
+
+        // Other routes aren't affected
+        let res = rate_limits
+            .retry(Some(2), "GET /other", || async {
+                (Ok(()), RateLimitInfo::default())
+            })
+            .await;
+        assert!(res.is_ok());
+        assert_eq!(rate_limits.waited(Some(2)), Duration::from_secs(0));
+
+        let res = rate_limits
+            .retry(Some(1), "GET /test", || async {
+                (Ok(()), RateLimitInfo::default())
+            })
+            .await;
+        assert!(res.is_ok());
+        assert!(rate_limits.waited(Some(1)) >= Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    async fn test_global_rate_limit() {
+        let rate_limits = RateLimits::new(Duration::from_secs(60));
+        rate_limits.update(
+            "GET /test",
+            &RateLimitInfo {
+                retry_after: Some(Duration::from_millis(50)),
+                global: true,
+                ..RateLimitInfo::default()
+            },
+        );
+        let res = rate_limits
+            .retry(Some(2), "GET /other", || async {
+                (Ok(()), RateLimitInfo::default())
+            })
+            .await;
+        assert!(res.is_ok());
+        assert!(rate_limits.waited(Some(2)) >= Duration::from_millis(40));
+        assert!(*rate_limits.budget.lock().unwrap() < Duration::from_secs(60));
+    }
+}
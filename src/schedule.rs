@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use std::{fmt, str::FromStr};
+
+use crate::config::{format_duration, parse_duration};
+
+/// When the daemon sweeps the configured channels.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Run at a fixed interval, measured from the start of the previous run.
+    Interval(Duration),
+    /// Run whenever the cron expression matches in the given timezone.
+    Cron {
+        schedule: Box<cron::Schedule>,
+        timezone: Tz,
+    },
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::Interval(Duration::minutes(1))
+    }
+}
+
+impl Schedule {
+    /// Parses either an interval like `30m` or a cron expression with
+    /// seconds like `0 0 2 * * *`. The timezone is only used for cron
+    /// expressions and defaults to UTC.
+    pub fn parse(input: &str, timezone: Option<&str>) -> Result<Self> {
+        let input = input.trim();
+        if !input.contains(char::is_whitespace) {
+            let interval = parse_duration(input).context("Invalid interval")?;
+            anyhow::ensure!(interval > Duration::zero(), "Interval must be positive");
+            return Ok(Schedule::Interval(interval));
+        }
+
+        let schedule = cron::Schedule::from_str(input).context("Invalid cron expression")?;
+        let timezone = match timezone {
+            Some(timezone) => timezone
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid timezone {:?}: {}", timezone, e))?,
+            None => Tz::UTC,
+        };
+        Ok(Schedule::Cron {
+            schedule: Box::new(schedule),
+            timezone,
+        })
+    }
+
+    /// Returns when the first run is due if the daemon starts at `now`.
+    /// Intervals start right away, cron schedules wait for their first slot.
+    pub fn first_run(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(_) => Some(now),
+            Schedule::Cron { .. } => self.next_after(now),
+        }
+    }
+
+    /// Returns the slot following a run that started at `started`, or `None`
+    /// if the cron expression never matches again.
+    pub fn next_after(&self, started: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(interval) => Some(started + *interval),
+            Schedule::Cron { schedule, timezone } => schedule
+                .after(&started.with_timezone(timezone))
+                .next()
+                .map(|next| next.with_timezone(&Utc)),
+        }
+    }
+
+    /// The timezone times of this schedule should be displayed in.
+    pub fn timezone(&self) -> Tz {
+        match self {
+            Schedule::Interval(_) => Tz::UTC,
+            Schedule::Cron { timezone, .. } => *timezone,
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Interval(interval) => write!(f, "every {}", format_duration(*interval)),
+            Schedule::Cron { schedule, timezone } => write!(f, "`{}` in {}", schedule, timezone),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_interval() {
+        let schedule = Schedule::parse("30m", None).unwrap();
+        let now = Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap();
+        assert_eq!(schedule.first_run(now), Some(now));
+        assert_eq!(schedule.next_after(now), Some(now + Duration::minutes(30)));
+        assert_eq!(schedule.to_string(), "every 30m");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Schedule::parse("0m", None).is_err());
+        assert!(Schedule::parse("30x", None).is_err());
+        assert!(Schedule::parse("0 0 25 * * *", None).is_err());
+        assert!(Schedule::parse("0 0 2 * * *", Some("Mars/Olympus")).is_err());
+    }
+
+    #[test]
+    fn test_parse_cron_with_timezone() {
+        let schedule = Schedule::parse("0 0 2 * * *", Some("Europe/Berlin")).unwrap();
+        let now = Utc.with_ymd_and_hms(2021, 1, 1, 12, 0, 0).unwrap();
+        // 02:00 in Berlin is 01:00 UTC in winter
+        let next = Utc.with_ymd_and_hms(2021, 1, 2, 1, 0, 0).unwrap();
+        assert_eq!(schedule.first_run(now), Some(next));
+        assert_eq!(schedule.next_after(now), Some(next));
+        assert_eq!(schedule.timezone(), Tz::Europe__Berlin);
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use chrono::Utc;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{error, info};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use crate::{health::Health, metrics};
+
+/// Serves the metrics and health checks over HTTP in the background.
+pub fn spawn(addr: SocketAddr, health: Arc<Health>) -> Result<()> {
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let health = health.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, health.clone()))) }
+    }));
+    info!(
+        "Serving metrics on http://{}/metrics and health checks on /healthz and /readyz",
+        addr
+    );
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Metrics server failed: {}", e);
+        }
+    });
+    Ok(())
+}
+
+async fn handle(req: Request<Body>, health: Arc<Health>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics::encode())),
+        (&Method::GET, "/healthz") => match health.check(Utc::now()) {
+            Ok(()) => Response::builder().body(Body::from("ok\n")),
+            Err(reason) => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(format!("{}\n", reason))),
+        },
+        (&Method::GET, "/readyz") if health.is_ready() => {
+            Response::builder().body(Body::from("ok\n"))
+        }
+        (&Method::GET, "/readyz") => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("not ready\n")),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found\n")),
+    };
+    Ok(response.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    async fn status(path: &str, health: &Arc<Health>) -> StatusCode {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        handle(req, health.clone()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_handle() {
+        let health = Arc::new(Health::new(1, Duration::hours(1)));
+        assert_eq!(status("/metrics", &health).await, StatusCode::OK);
+        assert_eq!(status("/foo", &health).await, StatusCode::NOT_FOUND);
+
+        assert_eq!(status("/healthz", &health).await, StatusCode::OK);
+        assert_eq!(
+            status("/readyz", &health).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        health.set_ready(true);
+        assert_eq!(status("/readyz", &health).await, StatusCode::OK);
+        health.run_finished(false);
+        assert_eq!(
+            status("/healthz", &health).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
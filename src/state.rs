@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Remembers how far each channel has been swept, so later sweeps only need
+/// to fetch the messages that expired since. The state is kept in memory and
+/// written to a JSON file if a path is configured.
+#[derive(Debug, Default)]
+pub struct State {
+    path: Option<PathBuf>,
+    channels: Mutex<HashMap<u64, ChannelState>>,
+}
+
+/// The progress of a single channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelState {
+    /// Every message older than this id was deleted or is kept by the rule.
+    pub swept_until: u64,
+    /// Identifies the rule the channel was swept with. If the rule changes,
+    /// kept messages might have to be deleted after all.
+    pub rule: String,
+    /// When the channel was last scanned from the cutoff to its beginning.
+    /// Kept messages, e.g. pinned ones, can change, so this is repeated
+    /// regularly. Missing in state files of older versions.
+    #[serde(default)]
+    pub full_scan_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    channels: HashMap<u64, ChannelState>,
+}
+
+impl State {
+    /// Loads the state from the given path. A missing or unreadable file is
+    /// not an error, all channels are scanned fully in that case.
+    pub fn load(path: Option<&Path>) -> Self {
+        let channels = match path {
+            Some(path) => match read(path) {
+                Ok(Some(state_file)) => state_file.channels,
+                Ok(None) => {
+                    info!("No state at {}, starting fresh", path.display());
+                    HashMap::new()
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not load state, all channels will be scanned fully: {:?}",
+                        e
+                    );
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+        State {
+            path: path.map(Path::to_path_buf),
+            channels: Mutex::new(channels),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the progress of the channel if it was swept with the given
+    /// rule.
+    pub fn get(&self, channel_id: u64, rule: &str) -> Option<ChannelState> {
+        let channels = self.channels.lock().unwrap();
+        channels
+            .get(&channel_id)
+            .filter(|channel_state| channel_state.rule == rule)
+            .cloned()
+    }
+
+    pub fn update(&self, channel_id: u64, channel_state: ChannelState) {
+        self.channels
+            .lock()
+            .unwrap()
+            .insert(channel_id, channel_state);
+    }
+
+    /// Writes the state to its file, if there is one. The file is replaced
+    /// atomically, so it's never left half-written.
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let state_file = StateFile {
+            channels: self.channels.lock().unwrap().clone(),
+        };
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&state_file)?)
+            .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> Result<Option<StateFile>> {
+    let input = match fs::read(path) {
+        Ok(input) => input,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+    };
+    let state_file = serde_json::from_slice(&input)
+        .with_context(|| format!("Could not parse {}", path.display()))?;
+    Ok(Some(state_file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!(
+            "discord-retention-bot-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let path = temp_path("roundtrip");
+        let state = State::load(Some(&path));
+        assert_eq!(state.get(1, "1d"), None);
+        let channel_state = ChannelState {
+            swept_until: 42,
+            rule: "1d".to_string(),
+            full_scan_at: Some(Utc::now()),
+        };
+        state.update(1, channel_state.clone());
+        state.save().unwrap();
+
+        let state = State::load(Some(&path));
+        assert_eq!(state.get(1, "1d"), Some(channel_state));
+        assert_eq!(state.get(1, "2d"), None); // the rule changed
+        assert_eq!(state.get(2, "1d"), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_state_corrupt() {
+        let path = temp_path("corrupt");
+        fs::write(&path, "{\"channels\": ").unwrap();
+        let state = State::load(Some(&path));
+        assert_eq!(state.get(1, "1d"), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_state_without_full_scan() {
+        let path = temp_path("without-full-scan");
+        fs::write(
+            &path,
+            r#"{"channels": {"1": {"swept_until": 42, "rule": "1d"}}}"#,
+        )
+        .unwrap();
+        let state = State::load(Some(&path));
+        let channel_state = state.get(1, "1d").unwrap();
+        assert_eq!(channel_state.swept_until, 42);
+        assert_eq!(channel_state.full_scan_at, None);
+        fs::remove_file(&path).unwrap();
+    }
+}